@@ -0,0 +1,632 @@
+use evenio::prelude::*;
+use glam::{IVec3, Vec3};
+use serde::{
+    de::value::{Error as ValueError, StrDeserializer},
+    Deserialize,
+};
+use tracing::info;
+
+use crate::{
+    event::{CommandEvent, PlayerCommandEvent},
+    networking::{s2c, PacketString},
+    rank::Ranks,
+    world::{Block, ClientConnection, Player, Position},
+};
+
+pub fn add_command_handlers(world: &mut World, registry: CommandRegistry) {
+    world.add_handler(player_command_handler.low());
+    world.add_handler(help_command_handler.low());
+
+    let command_registry = world.spawn();
+    world.insert(command_registry, registry);
+}
+
+/// How a command argument is parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// The name of an online player.
+    Player,
+    /// A block name as written in the config, or a block ID.
+    Block,
+    /// Three whole numbers, each of which may instead be `~` or `~n` to be relative to the player.
+    Coordinates,
+    Integer,
+    /// Everything left on the line. Only makes sense as the last argument.
+    Rest,
+}
+
+#[derive(Debug, Clone)]
+pub enum ArgValue {
+    Player { entity_id: EntityId, name: String },
+    Block(Block),
+    Coordinates(IVec3),
+    Integer(i32),
+    Rest(String),
+}
+
+#[derive(Debug, Clone)]
+struct Arg {
+    name: String,
+    kind: ArgKind,
+    optional: bool,
+}
+
+/// A chat command, run by typing `/name` followed by its arguments.
+///
+/// Registering a command only makes the server parse it. Running it is up to a handler of [`CommandEvent`].
+#[derive(Debug, Clone)]
+pub struct Command {
+    name: String,
+    aliases: Vec<String>,
+    description: String,
+    usage: Option<String>,
+    permission: Option<String>,
+    args: Vec<Arg>,
+}
+
+impl Command {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_ascii_lowercase(),
+            aliases: Vec::new(),
+            description: String::new(),
+            usage: None,
+            permission: None,
+            args: Vec::new(),
+        }
+    }
+
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.aliases.push(alias.to_ascii_lowercase());
+        self
+    }
+
+    /// Shown by `/help`.
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = description.into();
+        self
+    }
+
+    /// Replaces the usage generated from the arguments, e.g. `<player> [reason]`.
+    pub fn usage(mut self, usage: &str) -> Self {
+        self.usage = Some(usage.into());
+        self
+    }
+
+    /// The permission node players need to use the command. Commands without one are available to everyone.
+    pub fn permission(mut self, node: &str) -> Self {
+        self.permission = Some(node.into());
+        self
+    }
+
+    pub fn arg(self, name: &str, kind: ArgKind) -> Self {
+        assert!(
+            self.args.iter().all(|arg| !arg.optional),
+            "Required argument {name} of /{} follows an optional one",
+            self.name
+        );
+
+        self.push_arg(name, kind, false)
+    }
+
+    /// Optional arguments can only be followed by other optional arguments.
+    pub fn optional_arg(self, name: &str, kind: ArgKind) -> Self {
+        self.push_arg(name, kind, true)
+    }
+
+    fn push_arg(mut self, name: &str, kind: ArgKind, optional: bool) -> Self {
+        self.args.push(Arg {
+            name: name.into(),
+            kind,
+            optional,
+        });
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    pub fn permission_node(&self) -> Option<&str> {
+        self.permission.as_deref()
+    }
+
+    /// How to type the command, e.g. `/kick <player> [reason]`.
+    pub fn usage_line(&self) -> String {
+        let usage = self.usage.clone().unwrap_or_else(|| {
+            self.args
+                .iter()
+                .map(|arg| match (arg.kind, arg.optional) {
+                    (ArgKind::Coordinates, false) => "<x y z>".to_string(),
+                    (ArgKind::Coordinates, true) => "[x y z]".to_string(),
+                    (_, false) => format!("<{}>", arg.name),
+                    (_, true) => format!("[{}]", arg.name),
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        });
+
+        if usage.is_empty() {
+            format!("/{}", self.name)
+        } else {
+            format!("/{} {usage}", self.name)
+        }
+    }
+
+    /// `players` are the online players to match player arguments against, and `position` is where the player running the command is.
+    fn parse(
+        &self,
+        mut input: &str,
+        position: Vec3,
+        players: &[(EntityId, &str)],
+    ) -> Result<CommandArgs, String> {
+        let usage = || format!("Usage: {}", self.usage_line());
+        let mut values = Vec::with_capacity(self.args.len());
+
+        for arg in &self.args {
+            if input.trim().is_empty() {
+                if arg.optional {
+                    break;
+                }
+
+                return Err(usage());
+            }
+
+            let value = match arg.kind {
+                ArgKind::Player => {
+                    let name = next_token(&mut input);
+                    let (entity_id, name) = players
+                        .iter()
+                        .find(|(_, player)| player.eq_ignore_ascii_case(name))
+                        .ok_or_else(|| format!("No player named {name} is online"))?;
+
+                    ArgValue::Player {
+                        entity_id: *entity_id,
+                        name: name.to_string(),
+                    }
+                },
+                ArgKind::Block => {
+                    let name = next_token(&mut input);
+                    ArgValue::Block(
+                        parse_block(name).ok_or_else(|| format!("Unknown block {name}"))?,
+                    )
+                },
+                ArgKind::Coordinates => {
+                    let origin = position.floor().as_ivec3();
+                    let mut coordinates = IVec3::ZERO;
+
+                    for axis in 0..3 {
+                        let token = next_token(&mut input);
+                        coordinates[axis] = parse_coordinate(token, origin[axis])
+                            .ok_or_else(|| "Coordinates must be whole numbers or ~".to_string())?;
+                    }
+
+                    ArgValue::Coordinates(coordinates)
+                },
+                ArgKind::Integer => {
+                    let token = next_token(&mut input);
+                    ArgValue::Integer(
+                        token
+                            .parse()
+                            .map_err(|_| format!("{token} is not a whole number"))?,
+                    )
+                },
+                ArgKind::Rest => ArgValue::Rest(std::mem::take(&mut input).trim().to_string()),
+            };
+
+            values.push(value);
+        }
+
+        if !input.trim().is_empty() {
+            return Err(usage());
+        }
+
+        Ok(CommandArgs(values))
+    }
+}
+
+/// Splits off the next word of `input`. Empty once `input` runs out.
+fn next_token<'a>(input: &mut &'a str) -> &'a str {
+    let trimmed = input.trim_start();
+    let (token, rest) = trimmed
+        .split_once(char::is_whitespace)
+        .unwrap_or((trimmed, ""));
+    *input = rest;
+    token
+}
+
+fn parse_block(name: &str) -> Option<Block> {
+    if let Ok(id) = name.parse::<u8>() {
+        return enum_primitive::FromPrimitive::from_u8(id);
+    }
+
+    Block::deserialize(StrDeserializer::<ValueError>::new(
+        &name.to_ascii_lowercase(),
+    ))
+    .ok()
+}
+
+fn parse_coordinate(token: &str, origin: i32) -> Option<i32> {
+    match token.strip_prefix('~') {
+        Some("") => Some(origin),
+        Some(offset) => origin.checked_add(offset.parse().ok()?),
+        None => token.parse().ok(),
+    }
+}
+
+/// The arguments of a [`CommandEvent`], in the order the command declares them. Optional arguments that weren't given are missing from the end.
+#[derive(Debug, Clone, Default)]
+pub struct CommandArgs(Vec<ArgValue>);
+
+impl CommandArgs {
+    pub fn get(&self, index: usize) -> Option<&ArgValue> {
+        self.0.get(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn player(&self, index: usize) -> Option<(EntityId, &str)> {
+        match self.get(index)? {
+            ArgValue::Player { entity_id, name } => Some((*entity_id, name)),
+            _ => None,
+        }
+    }
+
+    pub fn block(&self, index: usize) -> Option<Block> {
+        match self.get(index)? {
+            ArgValue::Block(block) => Some(*block),
+            _ => None,
+        }
+    }
+
+    pub fn coordinates(&self, index: usize) -> Option<IVec3> {
+        match self.get(index)? {
+            ArgValue::Coordinates(coordinates) => Some(*coordinates),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self, index: usize) -> Option<i32> {
+        match self.get(index)? {
+            ArgValue::Integer(integer) => Some(*integer),
+            _ => None,
+        }
+    }
+
+    pub fn rest(&self, index: usize) -> Option<&str> {
+        match self.get(index)? {
+            ArgValue::Rest(rest) => Some(rest),
+            _ => None,
+        }
+    }
+}
+
+/// The commands players can run.
+#[derive(Component, Default, Debug)]
+pub struct CommandRegistry {
+    commands: Vec<Command>,
+}
+
+impl CommandRegistry {
+    /// Replaces any command with the same name.
+    pub fn register(&mut self, command: Command) {
+        match self.commands.iter_mut().find(|c| c.name == command.name) {
+            Some(existing) => *existing = command,
+            None => self.commands.push(command),
+        }
+    }
+
+    /// Finds a command by its name or one of its aliases.
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        let name = name.to_ascii_lowercase();
+
+        self.commands
+            .iter()
+            .find(|c| c.name == name)
+            .or_else(|| self.commands.iter().find(|c| c.aliases.contains(&name)))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Command> {
+        self.commands.iter()
+    }
+}
+
+/// The `/help` command, registered by default.
+pub fn help_command() -> Command {
+    Command::new("help")
+        .alias("?")
+        .description("Lists commands, or explains one")
+        .optional_arg("command", ArgKind::Rest)
+}
+
+fn may_use(command: &Command, player: &Player, ranks: &Ranks) -> bool {
+    command
+        .permission
+        .as_deref()
+        .is_none_or(|node| ranks.has_permission(&player.name, node))
+}
+
+/// Sends `message` to a single client, split over as many chat lines as it takes.
+pub fn reply(connection: &ClientConnection, message: &str) {
+    for line in wrap(message) {
+        connection.sender.send(Box::new(s2c::MessagePacket {
+            player_id: -1,
+            message: PacketString::truncated(&line),
+        }));
+    }
+}
+
+/// Splits `message` into lines that fit in a [`PacketString`], breaking between words where possible.
+fn wrap(message: &str) -> Vec<String> {
+    // Clients only show ASCII, and it keeps splitting lines by length simple
+    let message = message
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '?' })
+        .collect::<String>();
+    let mut lines = Vec::new();
+    let mut line = String::new();
+
+    for word in message.split_whitespace() {
+        if !line.is_empty() && line.len() + 1 + word.len() > PacketString::LENGTH {
+            lines.push(std::mem::take(&mut line));
+        }
+
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+
+        while line.len() > PacketString::LENGTH {
+            let rest = line.split_off(PacketString::LENGTH);
+            lines.push(std::mem::replace(&mut line, rest));
+        }
+    }
+
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+}
+
+fn player_command_handler(
+    e: Receiver<PlayerCommandEvent>,
+    players: Fetcher<(EntityId, &Player, &Position, &ClientConnection)>,
+    Single(registry): Single<&CommandRegistry>,
+    Single(ranks): Single<&Ranks>,
+    mut sender: Sender<CommandEvent>,
+) {
+    let Ok((_, player, position, connection)) = players.get(e.event.entity_id) else {
+        return;
+    };
+
+    let mut input = e.event.line.as_str();
+    let name = next_token(&mut input);
+
+    info!("{} ran /{}", player.name, e.event.line);
+
+    let Some(command) = registry.get(name) else {
+        reply(connection, &format!("Unknown command /{name}, see /help"));
+        return;
+    };
+
+    if !may_use(command, player, ranks) {
+        reply(connection, &format!("You may not use /{}", command.name));
+        return;
+    }
+
+    let online = players
+        .iter()
+        .map(|(id, player, _, _)| (id, player.name.as_str()))
+        .collect::<Vec<_>>();
+
+    match command.parse(input, position.0, &online) {
+        Ok(args) => sender.send(CommandEvent {
+            entity_id: e.event.entity_id,
+            name: command.name.clone(),
+            args,
+        }),
+        Err(message) => reply(connection, &message),
+    }
+}
+
+fn help_command_handler(
+    e: Receiver<CommandEvent>,
+    players: Fetcher<(&Player, &ClientConnection)>,
+    Single(registry): Single<&CommandRegistry>,
+    Single(ranks): Single<&Ranks>,
+) {
+    if e.event.name != "help" {
+        return;
+    }
+    let Ok((player, connection)) = players.get(e.event.entity_id) else {
+        return;
+    };
+
+    let Some(name) = e.event.args.rest(0) else {
+        let mut names = registry
+            .iter()
+            .filter(|command| may_use(command, player, ranks))
+            .map(|command| command.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+
+        reply(connection, &format!("Commands: {}", names.join(", ")));
+        reply(connection, "Type /help <command> for more about one");
+        return;
+    };
+
+    let name = name.trim_start_matches('/');
+    match registry.get(name) {
+        Some(command) if may_use(command, player, ranks) => {
+            reply(connection, &command.usage_line());
+            if !command.description.is_empty() {
+                reply(connection, &command.description);
+            }
+            if !command.aliases.is_empty() {
+                reply(
+                    connection,
+                    &format!("Aliases: {}", command.aliases.join(", ")),
+                );
+            }
+        },
+        _ => reply(connection, &format!("Unknown command /{name}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn players(world: &mut World) -> Vec<(EntityId, &'static str)> {
+        vec![(world.spawn(), "Alice"), (world.spawn(), "bob")]
+    }
+
+    fn parse(command: &Command, input: &str) -> Result<CommandArgs, String> {
+        let mut world = World::new();
+        let players = players(&mut world);
+        command.parse(input, Vec3::new(10.5, 20., -3.5), &players)
+    }
+
+    #[test]
+    fn parses_players_case_insensitively() {
+        let command = Command::new("tp").arg("player", ArgKind::Player);
+
+        let args = parse(&command, "  alice ").unwrap();
+        assert_eq!(args.player(0).unwrap().1, "Alice");
+        assert_eq!(
+            parse(&command, "carol").unwrap_err(),
+            "No player named carol is online"
+        );
+    }
+
+    #[test]
+    fn rest_takes_the_remaining_line() {
+        let command = Command::new("kick")
+            .arg("player", ArgKind::Player)
+            .optional_arg("reason", ArgKind::Rest);
+
+        let args = parse(&command, "bob  spamming   \"the chat\" ").unwrap();
+        assert_eq!(args.rest(1), Some("spamming   \"the chat\""));
+
+        let args = parse(&command, "bob").unwrap();
+        assert_eq!(args.len(), 1);
+        assert_eq!(args.rest(1), None);
+    }
+
+    #[test]
+    fn coordinates_may_be_relative() {
+        let command = Command::new("goto").arg("position", ArgKind::Coordinates);
+
+        let args = parse(&command, "~ ~-2 ~5").unwrap();
+        assert_eq!(args.coordinates(0), Some(IVec3::new(10, 18, 1)));
+
+        let args = parse(&command, "1 -2 3").unwrap();
+        assert_eq!(args.coordinates(0), Some(IVec3::new(1, -2, 3)));
+
+        for bad in ["1 2", "1 2 x", "~x 2 3", "1.5 2 3", "~2147483647 0 0"] {
+            assert_eq!(
+                parse(&command, bad).unwrap_err(),
+                "Coordinates must be whole numbers or ~"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_blocks_by_name_or_id() {
+        let command = Command::new("fill").arg("block", ArgKind::Block);
+
+        assert_eq!(
+            parse(&command, "Stone").unwrap().block(0),
+            Some(Block::Stone)
+        );
+        assert_eq!(
+            parse(&command, "grass_block").unwrap().block(0),
+            Some(Block::GrassBlock)
+        );
+        assert_eq!(parse(&command, "7").unwrap().block(0), Some(Block::Bedrock));
+        assert_eq!(parse(&command, "dirtt").unwrap_err(), "Unknown block dirtt");
+        assert_eq!(parse(&command, "255").unwrap_err(), "Unknown block 255");
+    }
+
+    #[test]
+    fn wrong_arguments_show_the_usage() {
+        let command = Command::new("give")
+            .arg("player", ArgKind::Player)
+            .arg("amount", ArgKind::Integer);
+
+        assert_eq!(
+            parse(&command, "bob").unwrap_err(),
+            "Usage: /give <player> <amount>"
+        );
+        assert_eq!(
+            parse(&command, "bob 5 extra").unwrap_err(),
+            "Usage: /give <player> <amount>"
+        );
+        assert_eq!(
+            parse(&command, "bob five").unwrap_err(),
+            "five is not a whole number"
+        );
+        assert_eq!(parse(&command, "bob -5").unwrap().integer(1), Some(-5));
+    }
+
+    #[test]
+    fn usage_lines() {
+        let command = Command::new("Tp")
+            .arg("player", ArgKind::Player)
+            .optional_arg("position", ArgKind::Coordinates);
+        assert_eq!(command.usage_line(), "/tp <player> [x y z]");
+        assert_eq!(Command::new("help").usage_line(), "/help");
+        assert_eq!(Command::new("ban").usage("<ip>").usage_line(), "/ban <ip>");
+    }
+
+    #[test]
+    fn registry_finds_aliases() {
+        let mut registry = CommandRegistry::default();
+        registry.register(help_command());
+        registry.register(Command::new("tp").alias("teleport"));
+
+        assert_eq!(registry.get("TELEPORT").unwrap().name(), "tp");
+        assert_eq!(registry.get("?").unwrap().name(), "help");
+        assert!(registry.get("kick").is_none());
+    }
+
+    #[test]
+    fn wraps_between_words() {
+        let message = format!("{} {}", "a".repeat(40), "b".repeat(30));
+        assert_eq!(wrap(&message), vec!["a".repeat(40), "b".repeat(30)]);
+
+        let message = format!("{} {}", "a".repeat(31), "b".repeat(32));
+        assert_eq!(wrap(&message), vec![message.clone()]);
+
+        assert!(wrap("  ").is_empty());
+        assert_eq!(wrap("héllo"), vec!["h?llo"]);
+    }
+
+    #[test]
+    fn wraps_long_words() {
+        let word = "x".repeat(150);
+        let lines = wrap(&format!("hi {word} bye"));
+
+        // Words too long for a line of their own are split at the line length
+        assert_eq!(
+            lines,
+            vec![
+                "hi".to_string(),
+                "x".repeat(64),
+                "x".repeat(64),
+                format!("{} bye", "x".repeat(22)),
+            ]
+        );
+    }
+}
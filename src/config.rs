@@ -0,0 +1,457 @@
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::Parser;
+use glam::{uvec3, vec3, UVec3, Vec3};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    heartbeat::HeartbeatConfig,
+    networking::{
+        access::ThrottleConfig,
+        listener::{KeepaliveConfig, WebSocketConfig},
+        proxy::ProxyProtocolConfig,
+        queue::OutboundConfig,
+        ratelimit::{RateLimitConfig, RateLimits},
+        PacketString,
+    },
+    world::Block,
+    SOFTWARE_NAME,
+};
+
+pub const DEFAULT_CONFIG_PATH: &str = "vintage.toml";
+
+/// The maximum amount of players the protocol can address.
+pub const PLAYER_LIMIT: usize = 127;
+
+/// Settings loaded from the config file, environment and command line, in increasing order of precedence.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub server: ServerSettings,
+    pub network: NetworkConfig,
+    pub level: LevelConfig,
+    pub heartbeat: HeartbeatConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub name: String,
+    pub motd: String,
+    pub max_players: usize,
+    /// Slots on top of `max_players` that only players with the `vintage.join.full` permission may take.
+    pub reserved_slots: usize,
+    /// How many players may wait for a free slot. Players beyond that are turned away.
+    pub queue_size: usize,
+    /// What to do when a player logs in under a name that is already online.
+    pub duplicate_login: DuplicateLoginPolicy,
+    /// How long a session may go without sending anything before a new login may replace it regardless of `duplicate_login`.
+    pub stale_session_secs: u64,
+    /// The disconnect reason shown to players when the server shuts down.
+    pub shutdown_message: String,
+    /// Where player ranks and the permissions they grant are kept.
+    pub rank_file: String,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            name: "vintage".into(),
+            motd: format!("{SOFTWARE_NAME} server"),
+            max_players: PLAYER_LIMIT,
+            reserved_slots: 0,
+            queue_size: 5,
+            duplicate_login: DuplicateLoginPolicy::KickOld,
+            stale_session_secs: 10,
+            shutdown_message: "Server closed".into(),
+            rank_file: "ranks.toml".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLoginPolicy {
+    /// Turns the new login away.
+    Reject,
+    /// Kicks the session that is already online.
+    KickOld,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub bind: String,
+    pub websocket: WebSocketConfig,
+    pub outbound: OutboundConfig,
+    pub keepalive: KeepaliveConfig,
+    pub rate_limit: RateLimitConfig,
+    pub throttle: ThrottleConfig,
+    /// Where IP bans are kept.
+    pub ban_file: String,
+    pub proxy_protocol: ProxyProtocolConfig,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:8080".into(),
+            websocket: WebSocketConfig::default(),
+            outbound: OutboundConfig::default(),
+            keepalive: KeepaliveConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            throttle: ThrottleConfig::default(),
+            ban_file: "bans.toml".into(),
+            proxy_protocol: ProxyProtocolConfig::default(),
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn port(&self) -> u16 {
+        self.bind
+            .parse::<SocketAddr>()
+            .map(|addr| addr.port())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LevelConfig {
+    pub path: String,
+    /// Only used when generating a new level.
+    pub dimensions: UVec3,
+    /// The height of the grass layer of newly generated levels.
+    pub ground_height: u32,
+    pub save_interval_secs: u64,
+    pub spawn: SpawnConfig,
+    pub build: BuildConfig,
+}
+
+impl Default for LevelConfig {
+    fn default() -> Self {
+        Self {
+            path: "./level.bin".into(),
+            dimensions: uvec3(128, 64, 128),
+            ground_height: 32,
+            save_interval_secs: 60,
+            spawn: SpawnConfig::default(),
+            build: BuildConfig::default(),
+        }
+    }
+}
+
+impl LevelConfig {
+    pub fn save_interval(&self) -> Duration {
+        Duration::from_secs(self.save_interval_secs)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpawnConfig {
+    pub position: Vec3,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+impl Default for SpawnConfig {
+    fn default() -> Self {
+        Self {
+            position: vec3(16.0, 34.0, 16.0),
+            pitch: 0.,
+            yaw: 0.,
+        }
+    }
+}
+
+/// Which block changes players may make. Refused changes are reverted on the client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BuildConfig {
+    /// How far from a block, in blocks, players may change it.
+    pub max_reach: f32,
+    /// The blocks players may place.
+    pub placeable: Vec<Block>,
+    /// The blocks players may not break.
+    pub unbreakable: Vec<Block>,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        use Block::*;
+
+        Self {
+            // The client reaches 5 blocks, the rest makes up for position updates lagging behind
+            max_reach: 8.,
+            // The blocks of the 0.30 inventory
+            placeable: vec![
+                Stone,
+                Cobblestone,
+                Bricks,
+                Dirt,
+                Planks,
+                Woord,
+                Leaves,
+                Glass,
+                Slab,
+                MossyCobbleStone,
+                Sapling,
+                Flower,
+                Rose,
+                BrownMushroom,
+                RedMushroom,
+                Sand,
+                Gravel,
+                Sponge,
+                RedCloth,
+                OrangeCloth,
+                YellowCloth,
+                ChartreuseCloth,
+                GreenCloth,
+                SpringGreenCloth,
+                CyanCloth,
+                CapriCloth,
+                UltramarineCloth,
+                PurpleCloth,
+                VioletCloth,
+                MagentaCloth,
+                RoseCloth,
+                DarkGreyCloth,
+                LightGreyCloth,
+                WhiteCloth,
+                CoalOre,
+                IronOre,
+                GoldOre,
+                BlockOfIron,
+                BlockOfGold,
+                Bookshelf,
+                TNT,
+                Obsidian,
+            ],
+            unbreakable: vec![
+                Bedrock,
+                FlowingWater,
+                StationaryWater,
+                FlowingLava,
+                StationaryLava,
+            ],
+        }
+    }
+}
+
+/// A Minecraft Classic 0.30 compatible server.
+///
+/// Every override can also be given as an environment variable.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to the config file, created with the default settings if it doesn't exist
+    #[arg(short, long, env = "VINTAGE_CONFIG", default_value = DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+
+    #[arg(long, env = "VINTAGE_BIND")]
+    pub bind: Option<String>,
+
+    #[arg(long, env = "VINTAGE_NAME")]
+    pub name: Option<String>,
+
+    #[arg(long, env = "VINTAGE_MOTD")]
+    pub motd: Option<String>,
+
+    #[arg(long, env = "VINTAGE_MAX_PLAYERS")]
+    pub max_players: Option<usize>,
+
+    #[arg(long, env = "VINTAGE_LEVEL")]
+    pub level: Option<String>,
+
+    #[arg(long, env = "VINTAGE_HEARTBEAT_URL")]
+    pub heartbeat_url: Option<String>,
+
+    #[arg(long, env = "VINTAGE_HEARTBEAT")]
+    pub heartbeat: Option<bool>,
+}
+
+impl ServerConfig {
+    /// Loads the config file named in `args`, writing out the defaults first if it doesn't exist, then applies the overrides and validates the result.
+    pub fn load(args: &Args) -> Result<Self> {
+        let mut config = Self::load_or_create(&args.config)?;
+        config.apply_args(args);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            let config = Self::default();
+            fs::write(path, toml::to_string_pretty(&config)?)
+                .with_context(|| format!("Failed to write default config to {}", path.display()))?;
+            info!("Created default config at {}", path.display());
+
+            return Ok(config);
+        }
+
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config from {}", path.display()))?;
+
+        toml::from_str(&data).with_context(|| format!("Invalid config in {}", path.display()))
+    }
+
+    pub fn apply_args(&mut self, args: &Args) {
+        if let Some(bind) = &args.bind {
+            self.network.bind = bind.clone();
+        }
+
+        if let Some(name) = &args.name {
+            self.server.name = name.clone();
+        }
+
+        if let Some(motd) = &args.motd {
+            self.server.motd = motd.clone();
+        }
+
+        if let Some(max_players) = args.max_players {
+            self.server.max_players = max_players;
+        }
+
+        if let Some(level) = &args.level {
+            self.level.path = level.clone();
+        }
+
+        if let Some(url) = &args.heartbeat_url {
+            self.heartbeat.url = url.clone();
+        }
+
+        if let Some(enabled) = args.heartbeat {
+            self.heartbeat.enabled = enabled;
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_packet_string("server.name", &self.server.name)?;
+        validate_packet_string("server.motd", &self.server.motd)?;
+        validate_packet_string("server.shutdown_message", &self.server.shutdown_message)?;
+        ensure!(
+            (1..=PLAYER_LIMIT).contains(&self.server.max_players),
+            "server.max_players must be between 1 and {PLAYER_LIMIT}"
+        );
+        ensure!(
+            self.server.max_players + self.server.reserved_slots <= PLAYER_LIMIT,
+            "server.max_players and server.reserved_slots must add up to at most {PLAYER_LIMIT}"
+        );
+
+        self.network.bind.parse::<SocketAddr>().with_context(|| {
+            format!("network.bind is not a valid address: {}", self.network.bind)
+        })?;
+
+        if let Some(addr) = &self.network.websocket.addr {
+            addr.parse::<SocketAddr>().with_context(|| {
+                format!("network.websocket.addr is not a valid address: {addr}")
+            })?;
+        }
+        ensure!(
+            self.network.websocket.path.starts_with('/'),
+            "network.websocket.path must start with '/'"
+        );
+        ensure!(
+            self.network.outbound.max_pending > 0 && self.network.outbound.max_droppable > 0,
+            "network.outbound.max_pending and network.outbound.max_droppable must be greater than 0"
+        );
+        ensure!(
+            self.network.outbound.max_flush_delay_ms <= 1000,
+            "network.outbound.max_flush_delay_ms must be at most 1000"
+        );
+        ensure!(
+            self.network.keepalive.ping_interval_secs > 0
+                && self.network.keepalive.login_timeout_secs > 0,
+            "network.keepalive.ping_interval_secs and network.keepalive.login_timeout_secs must be greater than 0"
+        );
+
+        let rate_limit = &self.network.rate_limit;
+        RateLimits::from_config(rate_limit).context("Invalid network.rate_limit.packets")?;
+        ensure!(
+            std::iter::once(&rate_limit.default)
+                .chain(rate_limit.packets.values())
+                .all(|limit| limit.rate > 0. && limit.burst > 0),
+            "network.rate_limit rates and bursts must be greater than 0"
+        );
+        ensure!(
+            self.network.throttle.connects_per_minute == 0
+                || self.network.throttle.connect_burst > 0,
+            "network.throttle.connect_burst must be greater than 0"
+        );
+        ensure!(
+            !self.network.proxy_protocol.enabled
+                || !self.network.proxy_protocol.trusted_proxies.is_empty(),
+            "network.proxy_protocol.trusted_proxies must not be empty when it is enabled"
+        );
+
+        let dims = self.level.dimensions;
+        if dims.min_element() == 0 || dims.max_element() > i16::MAX as u32 {
+            bail!(
+                "level.dimensions must be between 1 and {} on each axis",
+                i16::MAX
+            );
+        }
+        ensure!(
+            self.level.ground_height < dims.y,
+            "level.ground_height must be below the level height"
+        );
+        ensure!(
+            self.level.save_interval_secs > 0,
+            "level.save_interval_secs must be greater than 0"
+        );
+
+        ensure!(
+            self.level.build.max_reach > 0.,
+            "level.build.max_reach must be greater than 0"
+        );
+        ensure!(
+            !self.level.build.placeable.contains(&Block::Air),
+            "level.build.placeable must not contain air"
+        );
+
+        let spawn = self.level.spawn.position;
+        ensure!(
+            spawn.cmpge(Vec3::ZERO).all() && spawn.cmplt(dims.as_vec3()).all(),
+            "level.spawn.position must be inside the level"
+        );
+
+        if self.heartbeat.enabled {
+            ensure!(
+                self.heartbeat.url.starts_with("http://")
+                    || self.heartbeat.url.starts_with("https://"),
+                "heartbeat.url must be an http or https URL"
+            );
+            ensure!(
+                self.heartbeat.interval_secs > 0,
+                "heartbeat.interval_secs must be greater than 0"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_packet_string(name: &str, value: &str) -> Result<()> {
+    ensure!(
+        value.is_ascii(),
+        "{name} must only contain ASCII characters"
+    );
+    ensure!(
+        value.len() <= PacketString::LENGTH,
+        "{name} must be at most {} characters long",
+        PacketString::LENGTH
+    );
+
+    Ok(())
+}
@@ -1,42 +1,166 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
 
+use enum_primitive::FromPrimitive;
 use evenio::prelude::*;
-use tokio::sync::broadcast;
-use tracing::{debug, info};
+use glam::UVec3;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, warn};
 
 use crate::{
+    command::{self, ArgKind, Command, CommandRegistry},
+    config::{DuplicateLoginPolicy, ServerConfig},
     event::{
-        PlayerDisconnectEvent, PlayerJoinEvent, PlayerMessageEvent, PlayerMoveEvent, SetBlockEvent,
+        BlockBreakEvent, BlockPlaceEvent, CommandEvent, CpeHandshakeEvent, PlayerCommandEvent,
+        PlayerDisconnectEvent, PlayerIdentEvent, PlayerJoinEvent, PlayerLoginCompleteEvent,
+        PlayerMessageEvent, PlayerMoveEvent, PlayerPreLoginEvent, PlayerSetBlockEvent,
+        PlayerTeleportEvent, PrePlayerMessageEvent, PrePlayerMoveEvent, PreSetBlockEvent,
+        RankSavedEvent, ServerStopEvent, SetBlockEvent, SetRankEvent,
     },
+    heartbeat::NameVerifier,
     networking::{
         self, c2s,
         s2c::{self, S2CPacket},
-        ClientPacketRegistry, FShort, PacketString, Short,
+        util::to_angle_byte,
+        Byte, ClientPacketRegistry, FShort, PacketString, Short,
+    },
+    rank::{RankSave, Ranks},
+    world::{
+        Block, BlockWorld, ClientConnection, Player, PlayerIdAllocator, Position, Rotation,
+        Sessions, TickEvent,
     },
-    world::{Block, BlockWorld, ClientConnection, Player, PlayerIdAllocator, Position, Rotation},
 };
 
-use self::config::PlayerSpawnLocation;
+use self::config::{BuildRules, PlayerLimit, PlayerSpawnLocation, ServerIdentity, SessionPolicy};
+
+/// Lets players change blocks at all.
+pub const BUILD_PERMISSION: &str = "vintage.build";
+/// Lets players place and break any block, regardless of [`crate::config::BuildConfig`]'s lists.
+pub const BUILD_BYPASS_PERMISSION: &str = "vintage.build.bypass";
+/// Lets players take the reserved slots, skipping the queue while one is free.
+pub const JOIN_FULL_PERMISSION: &str = "vintage.join.full";
 
+/// `ranks` decide what players may do, and are shared with anything else that needs them.
 pub fn add_default_handlers(
     world: &mut World,
     broadcaster: Arc<broadcast::Sender<Arc<Box<dyn S2CPacket>>>>,
+    config: &ServerConfig,
+    ranks: Ranks,
 ) {
     info!("Initialising default server configuration...");
 
+    world.add_handler(session_insert_handler);
+    world.add_handler(session_remove_handler);
+    world.add_handler(player_ident_handler.low());
+    world.add_handler(player_pre_login_handler.low());
     world.add_handler(player_join_handler.low());
+    world.add_handler(validate_set_block_handler.low());
+    world.add_handler(pre_set_block_handler.low());
     world.add_handler(set_block_handler.low());
     world.add_handler(player_spawn_handler.low());
     world.add_handler(player_disconnect_handler.low());
     world.add_handler(player_despawn_handler.low());
+    world.add_handler(admit_waiting_handler.low());
+    world.add_handler(idle_timeout_handler);
+    world.add_handler(login_timeout_handler);
+    world.add_handler(pre_player_move_handler.low());
     world.add_handler(player_move_handler.low());
+    world.add_handler(player_teleport_handler.low());
+    world.add_handler(pre_player_message_handler.low());
     world.add_handler(player_message_handler.low());
+    world.add_handler(tp_command_handler.low());
+    world.add_handler(kick_command_handler.low());
+    world.add_handler(rank_command_handler.low());
+    world.add_handler(set_rank_handler.low());
+    world.add_handler(rank_save_handler);
+    world.add_handler(rank_saved_handler.low());
+    world.add_handler(rank_saver_stop_handler);
+
+    let rank_saver = world.spawn();
+    world.insert(rank_saver, RankSaver::spawn());
+
+    let sessions = world.spawn();
+    world.insert(sessions, Sessions::default());
 
     let player_id_allocator = world.spawn();
     world.insert(player_id_allocator, PlayerIdAllocator::new_empty());
 
     let packet_broadcaster = world.spawn();
     world.insert(packet_broadcaster, PacketBroadcaster(broadcaster));
+
+    let server_config = world.spawn();
+    world.insert(
+        server_config,
+        ServerIdentity {
+            name: config.server.name.clone(),
+            motd: config.server.motd.clone(),
+        },
+    );
+    world.insert(
+        server_config,
+        PlayerSpawnLocation {
+            position: config.level.spawn.position,
+            pitch: config.level.spawn.pitch,
+            yaw: config.level.spawn.yaw,
+        },
+    );
+    world.insert(
+        server_config,
+        PlayerLimit {
+            max_players: config.server.max_players,
+            reserved_slots: config.server.reserved_slots,
+            queue_size: config.server.queue_size,
+        },
+    );
+    world.insert(
+        server_config,
+        SessionPolicy {
+            duplicate_login: config.server.duplicate_login,
+            stale_after: Duration::from_secs(config.server.stale_session_secs),
+            idle_timeout: config.network.keepalive.idle_timeout(),
+            login_timeout: config.network.keepalive.login_timeout(),
+        },
+    );
+    world.insert(
+        server_config,
+        BuildRules {
+            max_reach: config.level.build.max_reach,
+            placeable: config.level.build.placeable.iter().copied().collect(),
+            unbreakable: config.level.build.unbreakable.iter().copied().collect(),
+        },
+    );
+    world.insert(server_config, WaitingQueue::default());
+    world.insert(server_config, ranks);
+}
+
+pub fn add_default_commands(registry: &mut CommandRegistry) {
+    registry.register(command::help_command());
+    registry.register(
+        Command::new("tp")
+            .alias("teleport")
+            .description("Teleports you to another player")
+            .permission("vintage.command.tp")
+            .arg("player", ArgKind::Player),
+    );
+    registry.register(
+        Command::new("rank")
+            .description("Shows a player's rank, or gives them another")
+            .permission("vintage.command.rank")
+            .arg("player", ArgKind::Player)
+            .optional_arg("rank", ArgKind::Rest),
+    );
+    registry.register(
+        Command::new("kick")
+            .description("Disconnects a player")
+            .permission("vintage.command.kick")
+            .arg("player", ArgKind::Player)
+            .optional_arg("reason", ArgKind::Rest),
+    );
 }
 
 pub fn add_default_packets(registry: &mut ClientPacketRegistry) {
@@ -47,30 +171,374 @@ pub fn add_default_packets(registry: &mut ClientPacketRegistry) {
 }
 
 pub mod config {
+    use std::{collections::HashSet, time::Duration};
+
     use evenio::prelude::*;
     use glam::Vec3;
 
+    use crate::{config::DuplicateLoginPolicy, rank::Ranks, world::Block};
+
     #[derive(Component)]
     pub struct PlayerSpawnLocation {
         pub position: Vec3,
         pub pitch: f32,
         pub yaw: f32,
     }
+
+    /// The name and MOTD sent to joining players.
+    #[derive(Component)]
+    pub struct ServerIdentity {
+        pub name: String,
+        pub motd: String,
+    }
+
+    /// How many players may be logged in at once.
+    #[derive(Component)]
+    pub struct PlayerLimit {
+        pub max_players: usize,
+        /// Slots on top of `max_players` that only players with [`super::JOIN_FULL_PERMISSION`] may take.
+        pub reserved_slots: usize,
+        /// How many players may wait for a free slot.
+        pub queue_size: usize,
+    }
+
+    /// How to handle a login under a name that is already online.
+    #[derive(Component)]
+    pub struct SessionPolicy {
+        pub duplicate_login: DuplicateLoginPolicy,
+        /// Sessions that haven't sent anything for this long are replaced even when new logins are rejected, so half-open connections don't lock players out.
+        pub stale_after: Duration,
+        /// Players that haven't sent anything for this long are kicked.
+        pub idle_timeout: Option<Duration>,
+        /// Clients that were given a slot but haven't joined this long after are kicked.
+        pub login_timeout: Duration,
+    }
+
+    /// Which block changes players may make.
+    #[derive(Component)]
+    pub struct BuildRules {
+        /// How far from a block, in blocks, players may change it.
+        pub max_reach: f32,
+        pub placeable: HashSet<Block>,
+        pub unbreakable: HashSet<Block>,
+    }
+
+    impl PlayerLimit {
+        /// The number of players that may be logged in for `username` to be let in.
+        pub fn capacity_for(&self, username: &str, ranks: &Ranks) -> usize {
+            if ranks.has_permission(username, super::JOIN_FULL_PERMISSION) {
+                self.max_players + self.reserved_slots
+            } else {
+                self.max_players
+            }
+        }
+    }
+}
+
+/// Writes the rank file on its own thread, one change at a time so that older ranks never overwrite newer ones.
+#[derive(Component)]
+struct RankSaver {
+    jobs: Option<mpsc::UnboundedSender<(RankSave, RankSavedEvent)>>,
+    done: mpsc::UnboundedReceiver<(RankSavedEvent, anyhow::Result<()>)>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl RankSaver {
+    fn spawn() -> Self {
+        let (jobs, mut pending) = mpsc::unbounded_channel::<(RankSave, RankSavedEvent)>();
+        let (finished, done) = mpsc::unbounded_channel();
+
+        let thread = thread::Builder::new()
+            .name("rank saver".into())
+            .spawn(move || {
+                while let Some((save, saved)) = pending.blocking_recv() {
+                    let _ = finished.send((saved, save.write()));
+                }
+            })
+            .unwrap();
+
+        Self {
+            jobs: Some(jobs),
+            done,
+            thread: Some(thread),
+        }
+    }
 }
 
 #[derive(Component)]
 struct PacketBroadcaster(Arc<broadcast::Sender<Arc<Box<dyn S2CPacket>>>>);
 
+/// Marks clients that were given a player slot, from the moment they were given it until they leave.
+#[derive(Component)]
+struct Admitted {
+    since: Instant,
+}
+
+/// Marks an admitted session whose slot was handed straight to the session replacing it, so it isn't given to a waiting client.
+#[derive(Component)]
+struct Replaced;
+
+/// The name a client identified with, for telling apart sessions before they have joined.
+#[derive(Component)]
+struct LoginName(String);
+
+/// Clients waiting for a player slot, in the order they identified.
+#[derive(Component, Default)]
+struct WaitingQueue(VecDeque<WaitingClient>);
+
+struct WaitingClient {
+    entity_id: EntityId,
+    username: String,
+    cpe: bool,
+}
+
+type LoginSender<'a> = Sender<
+    'a,
+    (
+        PlayerJoinEvent,
+        CpeHandshakeEvent,
+        Despawn,
+        Insert<Admitted>,
+        Insert<LoginName>,
+        Insert<Replaced>,
+    ),
+>;
+
+type JoinSender<'a> = Sender<
+    'a,
+    (
+        PlayerLoginCompleteEvent,
+        Insert<Player>,
+        Insert<Position>,
+        Insert<Rotation>,
+        Despawn,
+    ),
+>;
+
+fn player_ident_handler(
+    e: Receiver<PlayerIdentEvent>,
+    connections: Fetcher<&ClientConnection>,
+    TrySingle(verifier): TrySingle<&NameVerifier>,
+    mut sender: Sender<(PlayerPreLoginEvent, Despawn)>,
+) {
+    let Ok(connection) = connections.get(e.event.entity_id) else {
+        return;
+    };
+
+    if let Ok(verifier) = verifier {
+        if !verifier.verify(
+            &e.event.username,
+            &e.event.verification_key,
+            connection.addr.ip(),
+        ) {
+            warn!(
+                "Failed to verify {} from {}",
+                e.event.username, connection.addr
+            );
+            connection.sender.kick("Could not verify username");
+            sender.despawn(e.event.entity_id);
+            return;
+        }
+    }
+
+    sender.send(PlayerPreLoginEvent::new(
+        e.event.entity_id,
+        e.event.username.clone(),
+        connection.addr,
+        e.event.cpe,
+    ));
+}
+
+fn player_pre_login_handler(
+    e: Receiver<PlayerPreLoginEvent>,
+    connections: Fetcher<&ClientConnection>,
+    sessions: Fetcher<(EntityId, &LoginName, &ClientConnection, Has<&Admitted>)>,
+    admitted: Fetcher<With<&Admitted>>,
+    Single((limit, policy, queue, ranks)): Single<(
+        &PlayerLimit,
+        &SessionPolicy,
+        &mut WaitingQueue,
+        &Ranks,
+    )>,
+    mut sender: LoginSender,
+) {
+    let Ok(connection) = connections.get(e.event.entity_id) else {
+        return;
+    };
+
+    if let Some(reason) = e.event.denial() {
+        info!("Denied login of {}: {reason}", e.event.username);
+        connection.sender.kick(reason);
+        sender.despawn(e.event.entity_id);
+        return;
+    }
+
+    // Anything else can't be sent back to other clients as it is
+    if !(1..=PacketString::LENGTH).contains(&e.event.username.len()) || !e.event.username.is_ascii()
+    {
+        info!("Rejected {:?}, it isn't a valid name", e.event.username);
+        connection.sender.kick("Invalid username");
+        sender.despawn(e.event.entity_id);
+        return;
+    }
+
+    let existing = sessions.iter().find(|(id, name, _, _)| {
+        *id != e.event.entity_id && name.0.eq_ignore_ascii_case(&e.event.username)
+    });
+
+    // The slot of a replaced session is handed straight to the new one
+    let mut replaced = None;
+    if let Some((old_id, _, old_connection, old_admitted)) = existing {
+        let stale = old_connection.activity.idle() >= policy.stale_after;
+
+        if policy.duplicate_login == DuplicateLoginPolicy::Reject && !stale {
+            info!("Rejected {}, they are already online", e.event.username);
+            connection.sender.kick("You are already logged in");
+            sender.despawn(e.event.entity_id);
+            return;
+        }
+
+        info!(
+            "{} logged in again from {}, replacing the session from {}",
+            e.event.username, connection.addr, old_connection.addr
+        );
+        old_connection
+            .sender
+            .kick("Logged in from another location");
+        queue.0.retain(|client| client.entity_id != old_id);
+        replaced = Some((old_id, old_admitted.get()));
+    }
+
+    sender.insert(e.event.entity_id, LoginName(e.event.username.clone()));
+
+    let client = WaitingClient {
+        entity_id: e.event.entity_id,
+        username: e.event.username.clone(),
+        cpe: e.event.cpe,
+    };
+
+    let online = admitted.iter().count() - replaced.map_or(0, |(_, admitted)| admitted as usize);
+
+    // Players that may take reserved slots skip the queue if one is free
+    let capacity = limit.capacity_for(&client.username, ranks);
+    let admit_now = online < capacity && (queue.0.is_empty() || capacity > limit.max_players);
+
+    // Before the new session joins, so that the old one's player id is free again
+    if let Some((old_id, old_admitted)) = replaced {
+        if old_admitted && admit_now {
+            sender.insert(old_id, Replaced);
+        }
+        sender.despawn(old_id);
+    }
+
+    if admit_now {
+        admit(client, &mut sender);
+    } else if queue.0.len() >= limit.queue_size {
+        info!("Turned away {}, the server is full", e.event.username);
+        connection.sender.kick("Server is full");
+        sender.despawn(e.event.entity_id);
+    } else {
+        info!("{} is waiting for a free slot", e.event.username);
+        queue.0.push_back(client);
+        send_queue_positions(queue, |id| connections.get(id).ok());
+    }
+}
+
+/// Lets the client continue logging in, starting with the CPE handshake if it asked for one.
+fn admit(client: WaitingClient, sender: &mut LoginSender) {
+    sender.insert(
+        client.entity_id,
+        Admitted {
+            since: Instant::now(),
+        },
+    );
+
+    if client.cpe {
+        sender.send(CpeHandshakeEvent {
+            entity_id: client.entity_id,
+            username: client.username,
+        });
+    } else {
+        sender.send(PlayerJoinEvent {
+            entity_id: client.entity_id,
+            username: client.username,
+            cpe: false,
+        });
+    }
+}
+
+fn send_queue_positions<'a>(
+    queue: &WaitingQueue,
+    connection: impl Fn(EntityId) -> Option<&'a ClientConnection>,
+) {
+    for (i, client) in queue.0.iter().enumerate() {
+        if let Some(connection) = connection(client.entity_id) {
+            connection.sender.send(Box::new(s2c::MessagePacket {
+                player_id: -1,
+                message: PacketString::truncated(&format!(
+                    "Server is full, you are #{} in the queue",
+                    i + 1
+                )),
+            }));
+        }
+    }
+}
+
+/// Lets waiting clients in when an admitted one leaves.
+fn admit_waiting_handler(
+    _: Receiver<Despawn, (With<&Admitted>, Not<&Replaced>)>,
+    connections: Fetcher<&ClientConnection>,
+    admitted: Fetcher<With<&Admitted>>,
+    Single((limit, queue, ranks)): Single<(&PlayerLimit, &mut WaitingQueue, &Ranks)>,
+    mut sender: LoginSender,
+) {
+    if queue.0.is_empty() {
+        return;
+    }
+
+    // The leaving client is only removed after this handler runs
+    let count = admitted.iter().count() - 1;
+    let mut let_in = 0;
+    while let Some(client) = queue.0.front() {
+        if count + let_in >= limit.capacity_for(&client.username, ranks) {
+            break;
+        }
+
+        let client = queue.0.pop_front().unwrap();
+        info!("{} was let in from the queue", client.username);
+        admit(client, &mut sender);
+        let_in += 1;
+    }
+
+    if let_in > 0 {
+        send_queue_positions(queue, |id| connections.get(id).ok());
+    }
+}
+
 fn player_join_handler(
     e: Receiver<PlayerJoinEvent>,
     connections: Fetcher<&ClientConnection>,
     players: Fetcher<(&Position, &Rotation, &Player)>,
     Single(block_world): Single<&BlockWorld>,
     Single(player_id_allocator): Single<&mut PlayerIdAllocator>,
-    mut sender: Sender<(Insert<Player>, Insert<Position>, Insert<Rotation>)>,
-    Single(spawn_location): Single<&PlayerSpawnLocation>,
+    mut sender: JoinSender,
+    Single((spawn_location, identity, ranks)): Single<(
+        &PlayerSpawnLocation,
+        &ServerIdentity,
+        &Ranks,
+    )>,
 ) {
-    let player_id = player_id_allocator.alloc(e.event.entity_id);
+    let Ok(player) = connections.get(e.event.entity_id) else {
+        return;
+    };
+
+    // Admitted clients never outnumber the player ids, so this takes a plugin sending its own PlayerJoinEvent
+    let Some(player_id) = player_id_allocator.alloc(e.event.entity_id) else {
+        warn!("No player id left for {}", e.event.username);
+        player.sender.kick("Server is full");
+        sender.despawn(e.event.entity_id);
+        return;
+    };
     sender.insert(
         e.event.entity_id,
         Player {
@@ -88,60 +556,84 @@ fn player_join_handler(
         },
     );
 
-    let player = connections.get(e.event.entity_id).unwrap();
     info!("Player addr: {}", player.addr);
 
-    player
-        .sender
-        .blocking_send(Box::new(s2c::ServerIdentPacket {
-            protocol_version: 7,
-            server_name: PacketString::from_str("vintage").unwrap(),
-            server_motd: PacketString::from_str("Vintage server").unwrap(),
-            user_type: 0x64,
-        }))
-        .unwrap();
-
-    s2c::util::send_world(block_world, &player.sender).unwrap();
-
-    player
-        .sender
-        .blocking_send(Box::new(s2c::PlayerTeleportPacket {
-            player_id: -1,
-            pitch: 0,
-            yaw: 0,
-            x: FShort::from(spawn_location.position.x),
-            y: FShort::from(spawn_location.position.y),
-            z: FShort::from(spawn_location.position.z),
-        }))
-        .unwrap();
-
-    player
-        .sender
-        .blocking_send(Box::new(s2c::SpawnPlayerPacket {
-            player_id: -1,
-            player_name: PacketString::from_str(&e.event.username).unwrap(),
-            x: FShort::from(spawn_location.position.x),
-            y: FShort::from(spawn_location.position.y),
-            z: FShort::from(spawn_location.position.z),
-            yaw: networking::util::to_angle_byte(spawn_location.yaw),
-            pitch: networking::util::to_angle_byte(spawn_location.pitch),
-        }))
-        .unwrap();
+    player.sender.send(Box::new(s2c::ServerIdentPacket {
+        protocol_version: player.protocol_version,
+        server_name: PacketString::from_str(&identity.name).unwrap(),
+        server_motd: PacketString::from_str(&identity.motd).unwrap(),
+        user_type: ranks.user_type(&e.event.username),
+    }));
+
+    s2c::util::send_world(block_world, player.protocol_version, &player.sender).unwrap();
+
+    player.sender.send(Box::new(s2c::PlayerTeleportPacket {
+        player_id: -1,
+        pitch: 0,
+        yaw: 0,
+        x: FShort::from(spawn_location.position.x),
+        y: FShort::from(spawn_location.position.y),
+        z: FShort::from(spawn_location.position.z),
+    }));
+
+    player.sender.send(Box::new(s2c::SpawnPlayerPacket {
+        player_id: -1,
+        player_name: PacketString::truncated(&e.event.username),
+        x: FShort::from(spawn_location.position.x),
+        y: FShort::from(spawn_location.position.y),
+        z: FShort::from(spawn_location.position.z),
+        yaw: networking::util::to_angle_byte(spawn_location.yaw),
+        pitch: networking::util::to_angle_byte(spawn_location.pitch),
+    }));
 
     // Populate world with other players
     for (pos, rot, other_player) in players.iter() {
-        player
-            .sender
-            .blocking_send(Box::new(s2c::SpawnPlayerPacket {
-                x: FShort::from(pos.0.x),
-                y: FShort::from(pos.0.y),
-                z: FShort::from(pos.0.z),
-                pitch: networking::util::to_angle_byte(rot.pitch),
-                yaw: networking::util::to_angle_byte(rot.yaw),
-                player_id: other_player.id,
-                player_name: PacketString::from_str(&other_player.name).unwrap(),
-            }))
-            .unwrap();
+        player.sender.send(Box::new(s2c::SpawnPlayerPacket {
+            x: FShort::from(pos.0.x),
+            y: FShort::from(pos.0.y),
+            z: FShort::from(pos.0.z),
+            pitch: networking::util::to_angle_byte(rot.pitch),
+            yaw: networking::util::to_angle_byte(rot.yaw),
+            player_id: other_player.id,
+            player_name: PacketString::truncated(&other_player.name),
+        }));
+    }
+
+    sender.send(PlayerLoginCompleteEvent {
+        entity_id: e.event.entity_id,
+        username: e.event.username.clone(),
+    });
+}
+
+/// Kicks idle players. The listener then disconnects them like any other client.
+fn idle_timeout_handler(
+    _: Receiver<TickEvent>,
+    players: Fetcher<(&Player, &ClientConnection)>,
+    Single(policy): Single<&SessionPolicy>,
+) {
+    let Some(idle_timeout) = policy.idle_timeout else {
+        return;
+    };
+
+    for (player, connection) in players.iter() {
+        if connection.activity.idle() >= idle_timeout && !connection.sender.is_closed() {
+            info!("Kicking {} for being idle", player.name);
+            connection.sender.kick("Timed out");
+        }
+    }
+}
+
+/// Kicks clients holding a slot that haven't joined in time, such as ones stalling the CPE handshake.
+fn login_timeout_handler(
+    _: Receiver<TickEvent>,
+    clients: Fetcher<(&LoginName, &Admitted, &ClientConnection, Not<&Player>)>,
+    Single(policy): Single<&SessionPolicy>,
+) {
+    for (name, admitted, connection, _) in clients.iter() {
+        if admitted.since.elapsed() >= policy.login_timeout && !connection.sender.is_closed() {
+            info!("Kicking {}, they didn't finish logging in", name.0);
+            connection.sender.kick("Took too long to log in");
+        }
     }
 }
 
@@ -151,31 +643,54 @@ fn player_spawn_handler(
     Single(spawn_location): Single<&PlayerSpawnLocation>,
 ) {
     for (connection, _) in clients.iter() {
-        connection
-            .sender
-            .blocking_send(Box::new(s2c::SpawnPlayerPacket {
-                player_id: e.event.component.id,
-                player_name: PacketString::from_str(&e.event.component.name).unwrap(),
-                x: FShort::from(spawn_location.position.x),
-                y: FShort::from(spawn_location.position.y),
-                z: FShort::from(spawn_location.position.z),
-                pitch: networking::util::to_angle_byte(spawn_location.pitch),
-                yaw: networking::util::to_angle_byte(spawn_location.yaw),
-            }))
-            .unwrap();
+        connection.sender.send(Box::new(s2c::SpawnPlayerPacket {
+            player_id: e.event.component.id,
+            player_name: PacketString::truncated(&e.event.component.name),
+            x: FShort::from(spawn_location.position.x),
+            y: FShort::from(spawn_location.position.y),
+            z: FShort::from(spawn_location.position.z),
+            pitch: networking::util::to_angle_byte(spawn_location.pitch),
+            yaw: networking::util::to_angle_byte(spawn_location.yaw),
+        }));
+    }
+}
+
+fn session_insert_handler(
+    e: Receiver<Insert<ClientConnection>, EntityId>,
+    Single(sessions): Single<&mut Sessions>,
+) {
+    sessions.insert(e.event.component.session, e.query);
+}
+
+fn session_remove_handler(
+    e: Receiver<Despawn, (EntityId, &ClientConnection)>,
+    Single(sessions): Single<&mut Sessions>,
+) {
+    let (id, connection) = e.query;
+
+    // A newer entity can't have taken the session, but don't rely on it
+    if sessions.get(connection.session) == Some(id) {
+        sessions.remove(connection.session);
     }
 }
 
 fn player_disconnect_handler(
     e: Receiver<PlayerDisconnectEvent>,
-    clients: Fetcher<(EntityId, &ClientConnection, With<&Player>)>,
+    Single(sessions): Single<&Sessions>,
+    clients: Fetcher<&ClientConnection>,
+    Single(queue): Single<&mut WaitingQueue>,
     mut sender: Sender<Despawn>,
 ) {
-    for (id, connection, _) in clients.iter() {
-        if connection.addr == e.event.0 {
-            sender.despawn(id);
-        }
+    let Some(id) = sessions.get(e.event.0) else {
+        return;
+    };
+
+    if let Some(i) = queue.0.iter().position(|client| client.entity_id == id) {
+        queue.0.remove(i);
+        send_queue_positions(queue, |id| clients.get(id).ok());
     }
+
+    sender.despawn(id);
 }
 
 fn player_despawn_handler(
@@ -190,23 +705,91 @@ fn player_despawn_handler(
     player_id_allocator.free(player.id);
     for (id, _, connection) in fetcher.iter() {
         if id != e.event.0 {
-            connection
-                .sender
-                .blocking_send(Box::new(s2c::DespawnPlayerPacket {
-                    player_id: player.id,
-                }))
-                .unwrap();
+            connection.sender.send(Box::new(s2c::DespawnPlayerPacket {
+                player_id: player.id,
+            }));
         }
     }
 }
 
+fn pre_player_move_handler(
+    e: Receiver<PrePlayerMoveEvent>,
+    players: Fetcher<(&Position, &Rotation)>,
+    mut sender: Sender<(PlayerMoveEvent, PlayerTeleportEvent)>,
+) {
+    let Ok((position, rotation)) = players.get(e.event.entity_id) else {
+        return;
+    };
+
+    if e.event.is_cancelled() {
+        sender.send(PlayerTeleportEvent {
+            entity_id: e.event.entity_id,
+            pos: position.0,
+            rot: *rotation,
+        });
+    } else if e.event.is_changed() {
+        sender.send(PlayerTeleportEvent {
+            entity_id: e.event.entity_id,
+            pos: e.event.pos,
+            rot: e.event.rot,
+        });
+    } else {
+        sender.send(PlayerMoveEvent {
+            entity_id: e.event.entity_id,
+            pos: e.event.pos,
+            rot: e.event.rot,
+        });
+    }
+}
+
+fn player_teleport_handler(
+    e: Receiver<PlayerTeleportEvent>,
+    mut players: Fetcher<(&mut Position, &mut Rotation, &Player, &ClientConnection)>,
+    connections: Fetcher<(EntityId, &ClientConnection)>,
+) {
+    let Ok((position, rotation, player, connection)) = players.get_mut(e.event.entity_id) else {
+        return;
+    };
+
+    connection.sender.send(Box::new(s2c::PlayerTeleportPacket {
+        player_id: -1,
+        x: FShort::from(e.event.pos.x),
+        y: FShort::from(e.event.pos.y),
+        z: FShort::from(e.event.pos.z),
+        yaw: to_angle_byte(e.event.rot.yaw),
+        pitch: to_angle_byte(e.event.rot.pitch),
+    }));
+
+    for (id, connection) in connections.iter() {
+        if id != e.event.entity_id {
+            s2c::util::send_player_move_packet(
+                position.0,
+                e.event.pos,
+                *rotation,
+                e.event.rot,
+                0.,
+                player.id,
+                &connection.sender,
+            );
+        }
+    }
+
+    position.0 = e.event.pos;
+    *rotation = e.event.rot;
+}
+
 fn player_move_handler(
     e: Receiver<PlayerMoveEvent>,
     mut players: Fetcher<(&mut Position, &mut Rotation, &Player)>,
     connections: Fetcher<(EntityId, &ClientConnection)>,
     Single(player_id_allocator): Single<&mut PlayerIdAllocator>,
 ) {
-    let (original_position, original_rotation, _) = players.get_mut(e.event.entity_id).unwrap();
+    let Ok((original_position, original_rotation, _)) = players.get_mut(e.event.entity_id) else {
+        return;
+    };
+    let Some(player_id) = player_id_allocator.get_player_id(e.event.entity_id) else {
+        return;
+    };
 
     for (id, connection) in connections.iter() {
         if id != e.event.entity_id {
@@ -216,12 +799,9 @@ fn player_move_handler(
                 *original_rotation,
                 e.event.rot,
                 3.,
-                player_id_allocator
-                    .get_player_id(e.event.entity_id)
-                    .unwrap(),
+                player_id,
                 &connection.sender,
-            )
-            .unwrap();
+            );
         }
     }
 
@@ -229,6 +809,123 @@ fn player_move_handler(
     *original_rotation = e.event.rot;
 }
 
+fn validate_set_block_handler(
+    e: Receiver<PlayerSetBlockEvent>,
+    players: Fetcher<(&Player, &Position, &ClientConnection)>,
+    Single(block_world): Single<&BlockWorld>,
+    Single((rules, ranks)): Single<(&BuildRules, &Ranks)>,
+    mut sender: Sender<PreSetBlockEvent>,
+) {
+    let Ok((player, position, connection)) = players.get(e.event.entity_id) else {
+        return;
+    };
+
+    // There is nothing to send back outside the level, and the client doesn't draw anything there
+    if !block_world.contains(e.event.pos) {
+        debug!(player = player.name, pos = ?e.event.pos, "Ignored block change outside the level");
+        return;
+    }
+
+    let pos = e.event.pos.as_uvec3();
+    let current = block_world.get_block(pos);
+
+    let checked = if ranks.has_permission(&player.name, BUILD_PERMISSION) {
+        let bypass = ranks.has_permission(&player.name, BUILD_BYPASS_PERMISSION);
+        check_block_change(rules, bypass, position, pos, current, e.event)
+    } else {
+        Err("not allowed to build")
+    };
+
+    match checked {
+        Ok(block) => sender.send(PreSetBlockEvent::new(
+            e.event.entity_id,
+            pos,
+            e.event.placed,
+            block,
+        )),
+        Err(reason) => {
+            debug!(player = player.name, ?pos, reason, "Refused block change");
+            send_block_back(connection, pos, current);
+        },
+    }
+}
+
+/// The client draws its block changes immediately, so refusing one means sending it the block that is actually there.
+fn send_block_back(connection: &ClientConnection, pos: UVec3, block: Block) {
+    connection.sender.send(Box::new(s2c::SetBlockPacket {
+        x: pos.x as Short,
+        y: pos.y as Short,
+        z: pos.z as Short,
+        block_type: block as Byte,
+    }));
+}
+
+/// Returns the block to place, or why the change isn't allowed. `bypass` ignores which blocks may be placed and broken.
+fn check_block_change(
+    rules: &BuildRules,
+    bypass: bool,
+    position: &Position,
+    pos: UVec3,
+    current: Block,
+    event: &PlayerSetBlockEvent,
+) -> Result<Block, &'static str> {
+    if position.0.distance(pos.as_vec3() + 0.5) > rules.max_reach {
+        return Err("out of reach");
+    }
+
+    if !event.placed {
+        if !bypass && rules.unbreakable.contains(&current) {
+            return Err("unbreakable block");
+        }
+
+        return Ok(Block::Air);
+    }
+
+    match Block::from_u8(event.block_id) {
+        Some(block) if bypass || rules.placeable.contains(&block) => Ok(block),
+        Some(_) => Err("block may not be placed"),
+        None => Err("unknown block"),
+    }
+}
+
+fn pre_set_block_handler(
+    e: Receiver<PreSetBlockEvent>,
+    connections: Fetcher<&ClientConnection>,
+    Single(block_world): Single<&BlockWorld>,
+    mut sender: Sender<(SetBlockEvent, BlockPlaceEvent, BlockBreakEvent)>,
+) {
+    let pos = e.event.pos();
+    let current = block_world.get_block(pos);
+
+    if e.event.is_cancelled() {
+        if let Ok(connection) = connections.get(e.event.entity_id) {
+            send_block_back(connection, pos, current);
+        }
+        return;
+    }
+
+    // The broadcast also corrects the player's client if the block was changed
+    sender.send(SetBlockEvent {
+        pos,
+        placed: e.event.placed,
+        block: e.event.block,
+    });
+
+    if e.event.placed {
+        sender.send(BlockPlaceEvent {
+            entity_id: e.event.entity_id,
+            pos,
+            block: e.event.block,
+        });
+    } else {
+        sender.send(BlockBreakEvent {
+            entity_id: e.event.entity_id,
+            pos,
+            block: current,
+        });
+    }
+}
+
 fn set_block_handler(
     e: Receiver<SetBlockEvent>,
     Single(block_world): Single<&mut BlockWorld>,
@@ -242,15 +939,218 @@ fn set_block_handler(
 
     block_world.set_block(e.event.pos, block);
 
-    broadcaster
-        .0
-        .send(Arc::new(Box::new(s2c::SetBlockPacket {
-            block_type: block as u8,
-            x: e.event.pos.x as Short,
-            y: e.event.pos.y as Short,
-            z: e.event.pos.z as Short,
-        })))
-        .unwrap();
+    // Fails only if nobody is connected to receive it
+    let _ = broadcaster.0.send(Arc::new(Box::new(s2c::SetBlockPacket {
+        block_type: block as u8,
+        x: e.event.pos.x as Short,
+        y: e.event.pos.y as Short,
+        z: e.event.pos.z as Short,
+    })));
+}
+
+fn pre_player_message_handler(
+    e: Receiver<PrePlayerMessageEvent>,
+    mut sender: Sender<(PlayerMessageEvent, PlayerCommandEvent)>,
+) {
+    if e.event.is_cancelled() {
+        return;
+    }
+
+    if let Some(line) = e.event.message.strip_prefix('/') {
+        sender.send(PlayerCommandEvent {
+            entity_id: e.event.entity_id,
+            line: line.to_string(),
+        });
+    } else {
+        sender.send(PlayerMessageEvent {
+            entity_id: e.event.entity_id,
+            message: e.event.message.clone(),
+        });
+    }
+}
+
+fn tp_command_handler(
+    e: Receiver<CommandEvent>,
+    players: Fetcher<(&Position, &Rotation)>,
+    mut sender: Sender<PlayerTeleportEvent>,
+) {
+    if e.event.name != "tp" {
+        return;
+    }
+    let Some((target, _)) = e.event.args.player(0) else {
+        return;
+    };
+    let Ok((position, rotation)) = players.get(target) else {
+        return;
+    };
+
+    sender.send(PlayerTeleportEvent {
+        entity_id: e.event.entity_id,
+        pos: position.0,
+        rot: *rotation,
+    });
+}
+
+fn kick_command_handler(e: Receiver<CommandEvent>, players: Fetcher<(&Player, &ClientConnection)>) {
+    if e.event.name != "kick" {
+        return;
+    }
+    let (Some((target, target_name)), Ok((player, connection))) =
+        (e.event.args.player(0), players.get(e.event.entity_id))
+    else {
+        return;
+    };
+    let Ok((_, target_connection)) = players.get(target) else {
+        return;
+    };
+
+    let reason = match e.event.args.rest(1) {
+        Some(reason) => reason.to_string(),
+        None => format!("Kicked by {}", player.name),
+    };
+
+    info!("{} kicked {target_name}: {reason}", player.name);
+    target_connection.sender.kick(&reason);
+    command::reply(connection, &format!("Kicked {target_name}"));
+}
+
+fn rank_command_handler(
+    e: Receiver<CommandEvent>,
+    connections: Fetcher<(&Player, &ClientConnection)>,
+    Single(ranks): Single<&Ranks>,
+    mut sender: Sender<SetRankEvent>,
+) {
+    if e.event.name != "rank" {
+        return;
+    }
+    let (Some((_, target_name)), Ok((player, connection))) =
+        (e.event.args.player(0), connections.get(e.event.entity_id))
+    else {
+        return;
+    };
+
+    let Some(rank) = e.event.args.rest(1) else {
+        command::reply(
+            connection,
+            &format!("{target_name} is {}", ranks.rank_of(target_name)),
+        );
+        return;
+    };
+
+    if ranks.get(rank).is_none() {
+        command::reply(
+            connection,
+            &format!("Unknown rank {rank}, try {}", ranks.ranks().join(", ")),
+        );
+        return;
+    }
+
+    // Players can only hand out ranks they have, and only to players that don't outrank them
+    let current = ranks.rank_of(target_name);
+    if !ranks.covers(&player.name, rank) || !ranks.covers(&player.name, &current) {
+        command::reply(
+            connection,
+            &format!("You can't change {target_name} from {current} to {rank}"),
+        );
+        return;
+    }
+
+    sender.send(SetRankEvent {
+        username: target_name.to_string(),
+        rank: rank.to_string(),
+        by: Some(e.event.entity_id),
+    });
+}
+
+fn set_rank_handler(
+    e: Receiver<SetRankEvent>,
+    players: Fetcher<(&Player, &ClientConnection)>,
+    Single(ranks): Single<&Ranks>,
+    Single(saver): Single<&RankSaver>,
+) {
+    let save = match ranks.change_rank(&e.event.username, &e.event.rank) {
+        Ok(save) => save,
+        Err(err) => {
+            warn!(
+                "Failed to give {} the rank {}: {err}",
+                e.event.username, e.event.rank
+            );
+            if let Some((_, connection)) = e.event.by.and_then(|by| players.get(by).ok()) {
+                command::reply(
+                    connection,
+                    &format!(
+                        "Failed to give {} the rank {}",
+                        e.event.username, e.event.rank
+                    ),
+                );
+            }
+            return;
+        },
+    };
+
+    // The requester is told once the rank file was written, by rank_saved_handler
+    if let Some(jobs) = &saver.jobs {
+        let _ = jobs.send((
+            save,
+            RankSavedEvent {
+                username: e.event.username.clone(),
+                rank: e.event.rank.clone(),
+                by: e.event.by,
+            },
+        ));
+    }
+
+    let Some((player, connection)) = players
+        .iter()
+        .find(|(player, _)| player.name.eq_ignore_ascii_case(&e.event.username))
+    else {
+        return;
+    };
+
+    connection.sender.send(Box::new(s2c::UpdateUserTypePacket {
+        user_type: ranks.user_type(&player.name),
+    }));
+    command::reply(connection, &format!("You are now {}", e.event.rank));
+}
+
+/// Passes on the outcome of rank changes that the [`RankSaver`] finished writing.
+fn rank_save_handler(
+    _: Receiver<TickEvent>,
+    connections: Fetcher<&ClientConnection>,
+    Single(saver): Single<&mut RankSaver>,
+    mut sender: Sender<RankSavedEvent>,
+) {
+    while let Ok((saved, result)) = saver.done.try_recv() {
+        match result {
+            Ok(()) => sender.send(saved),
+            Err(err) => {
+                warn!("Failed to save the rank of {}: {err}", saved.username);
+                if let Some(connection) = saved.by.and_then(|by| connections.get(by).ok()) {
+                    command::reply(
+                        connection,
+                        &format!("Failed to save the rank of {}", saved.username),
+                    );
+                }
+            },
+        }
+    }
+}
+
+fn rank_saved_handler(e: Receiver<RankSavedEvent>, connections: Fetcher<&ClientConnection>) {
+    if let Some(connection) = e.event.by.and_then(|by| connections.get(by).ok()) {
+        command::reply(
+            connection,
+            &format!("{} is now {}", e.event.username, e.event.rank),
+        );
+    }
+}
+
+/// Waits for ranks that are still being written, so they aren't lost when the process exits.
+fn rank_saver_stop_handler(_: Receiver<ServerStopEvent>, Single(saver): Single<&mut RankSaver>) {
+    saver.jobs = None;
+    if let Some(thread) = saver.thread.take() {
+        let _ = thread.join();
+    }
 }
 
 fn player_message_handler(
@@ -260,21 +1160,17 @@ fn player_message_handler(
     players: Fetcher<&Player>,
 ) {
     debug!("Handling player message");
-    let player_id = player_id_allocator
-        .get_player_id(e.event.entity_id)
-        .unwrap();
-    let player = players.get(e.event.entity_id).unwrap();
+    let (Some(player_id), Ok(player)) = (
+        player_id_allocator.get_player_id(e.event.entity_id),
+        players.get(e.event.entity_id),
+    ) else {
+        return;
+    };
 
     info!("Player {}: {}", player.name, e.event.message);
 
-    broadcaster
-        .0
-        .send(Arc::new(Box::new(s2c::MessagePacket {
-            message: PacketString::from_str(
-                format!("{}: {}", player.name, &e.event.message).as_str(),
-            )
-            .unwrap(),
-            player_id,
-        })))
-        .unwrap();
+    let _ = broadcaster.0.send(Arc::new(Box::new(s2c::MessagePacket {
+        message: PacketString::truncated(&format!("{}: {}", player.name, &e.event.message)),
+        player_id,
+    })));
 }
@@ -1,9 +1,53 @@
 use std::net::SocketAddr;
 
 use evenio::{entity::EntityId, event::Event};
-use glam::{UVec3, Vec3};
+use glam::{IVec3, UVec3, Vec3};
 
-use crate::world::{Block, Rotation};
+use crate::{
+    command::CommandArgs,
+    networking::{extension::Int, listener::SessionId, Byte},
+    world::{Block, Rotation},
+};
+
+/// Sent when a client identifies itself, before it is allowed to join.
+#[derive(Debug, Event)]
+pub struct PlayerIdentEvent {
+    pub entity_id: EntityId,
+    pub username: String,
+    pub verification_key: String,
+    pub cpe: bool,
+}
+
+/// Sent once a client's name was verified, before it is given a player slot. Denying the login kicks the client with the reason.
+#[derive(Debug, Event)]
+pub struct PlayerPreLoginEvent {
+    pub entity_id: EntityId,
+    pub username: String,
+    pub addr: SocketAddr,
+    pub cpe: bool,
+    denial: Option<String>,
+}
+
+impl PlayerPreLoginEvent {
+    pub fn new(entity_id: EntityId, username: String, addr: SocketAddr, cpe: bool) -> Self {
+        Self {
+            entity_id,
+            username,
+            addr,
+            cpe,
+            denial: None,
+        }
+    }
+
+    pub fn deny(&mut self, reason: impl Into<String>) {
+        self.denial = Some(reason.into());
+    }
+
+    /// The reason the login was denied with, if it was.
+    pub fn denial(&self) -> Option<&str> {
+        self.denial.as_deref()
+    }
+}
 
 #[derive(Debug, Event)]
 pub struct PlayerJoinEvent {
@@ -12,6 +56,81 @@ pub struct PlayerJoinEvent {
     pub cpe: bool,
 }
 
+/// Sent once a joining player was sent the level and spawned in it.
+#[derive(Debug, Event)]
+pub struct PlayerLoginCompleteEvent {
+    pub entity_id: EntityId,
+    pub username: String,
+}
+
+/// Sent instead of [`PlayerJoinEvent`] when a client signals CPE support. The join is deferred until the extension lists have been exchanged.
+#[derive(Debug, Event)]
+pub struct CpeHandshakeEvent {
+    pub entity_id: EntityId,
+    pub username: String,
+}
+
+#[derive(Debug, Event)]
+pub struct ExtInfoEvent {
+    pub entity_id: EntityId,
+    pub app_name: String,
+    pub extension_count: u16,
+}
+
+#[derive(Debug, Event)]
+pub struct ExtEntryEvent {
+    pub entity_id: EntityId,
+    pub ext_name: String,
+    pub version: Int,
+}
+
+/// Sent when a player asks to change a block. Only turned into a [`PreSetBlockEvent`] if the change is inside the level and allowed by [`crate::config::BuildConfig`], otherwise the client is sent the block back.
+#[derive(Debug, Event)]
+pub struct PlayerSetBlockEvent {
+    pub entity_id: EntityId,
+    /// As sent by the client, so possibly outside the level.
+    pub pos: IVec3,
+    pub placed: bool,
+    /// As sent by the client, so possibly not a block.
+    pub block_id: Byte,
+}
+
+/// Sent before a player's block change is applied, so handlers that run before the default ones can change the block or cancel the change. The player is sent the original block back if it is cancelled.
+#[derive(Debug, Event)]
+pub struct PreSetBlockEvent {
+    pub entity_id: EntityId,
+    pos: UVec3,
+    pub placed: bool,
+    pub block: Block,
+    cancelled: bool,
+}
+
+impl PreSetBlockEvent {
+    pub fn new(entity_id: EntityId, pos: UVec3, placed: bool, block: Block) -> Self {
+        Self {
+            entity_id,
+            pos,
+            placed,
+            block,
+            cancelled: false,
+        }
+    }
+
+    /// Can't be changed, as the player's client has already drawn the change there.
+    pub fn pos(&self) -> UVec3 {
+        self.pos
+    }
+
+    pub fn cancel(&mut self) {
+        self.cancelled = true;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+}
+
+/// Changes a block for everyone. `pos` must be inside the level.
 #[derive(Debug, Event)]
 pub struct SetBlockEvent {
     pub pos: UVec3,
@@ -19,6 +138,60 @@ pub struct SetBlockEvent {
     pub block: Block,
 }
 
+/// Sent once a player placed a block, after it was set.
+#[derive(Debug, Event)]
+pub struct BlockPlaceEvent {
+    pub entity_id: EntityId,
+    pub pos: UVec3,
+    pub block: Block,
+}
+
+/// Sent once a player broke a block, after it was replaced with air.
+#[derive(Debug, Event)]
+pub struct BlockBreakEvent {
+    pub entity_id: EntityId,
+    pub pos: UVec3,
+    /// The block that was broken.
+    pub block: Block,
+}
+
+/// Sent before a player's movement is applied, so handlers that run before the default ones can change where the player ends up or cancel the movement. A [`PlayerTeleportEvent`] moves the player back if it is cancelled, or to where they end up if that was changed.
+#[derive(Debug, Event)]
+pub struct PrePlayerMoveEvent {
+    pub entity_id: EntityId,
+    pub pos: Vec3,
+    pub rot: Rotation,
+    reported_pos: Vec3,
+    reported_rot: Rotation,
+    cancelled: bool,
+}
+
+impl PrePlayerMoveEvent {
+    pub fn new(entity_id: EntityId, pos: Vec3, rot: Rotation) -> Self {
+        Self {
+            entity_id,
+            pos,
+            rot,
+            reported_pos: pos,
+            reported_rot: rot,
+            cancelled: false,
+        }
+    }
+
+    /// Whether `pos` or `rot` differ from where the client says the player is.
+    pub fn is_changed(&self) -> bool {
+        self.pos != self.reported_pos || self.rot != self.reported_rot
+    }
+
+    pub fn cancel(&mut self) {
+        self.cancelled = true;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+}
+
 #[derive(Debug, Event)]
 pub struct PlayerMoveEvent {
     pub entity_id: EntityId,
@@ -26,11 +199,111 @@ pub struct PlayerMoveEvent {
     pub rot: Rotation,
 }
 
+/// Moves a player, including on their own client.
+#[derive(Debug, Event)]
+pub struct PlayerTeleportEvent {
+    pub entity_id: EntityId,
+    pub pos: Vec3,
+    pub rot: Rotation,
+}
+
+/// Sent before a player's chat message is broadcast, so handlers that run before the default ones can change or cancel it.
+#[derive(Debug, Event)]
+pub struct PrePlayerMessageEvent {
+    pub entity_id: EntityId,
+    pub message: String,
+    cancelled: bool,
+}
+
+impl PrePlayerMessageEvent {
+    pub fn new(entity_id: EntityId, message: String) -> Self {
+        Self {
+            entity_id,
+            message,
+            cancelled: false,
+        }
+    }
+
+    pub fn cancel(&mut self) {
+        self.cancelled = true;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+}
+
 #[derive(Debug, Event)]
 pub struct PlayerMessageEvent {
     pub entity_id: EntityId,
     pub message: String,
 }
 
+/// Sent instead of [`PlayerMessageEvent`] for chat messages starting with `/`.
 #[derive(Debug, Event)]
-pub struct PlayerDisconnectEvent(pub SocketAddr);
+pub struct PlayerCommandEvent {
+    pub entity_id: EntityId,
+    /// The message without the leading `/`.
+    pub line: String,
+}
+
+/// Sent when a player runs a registered command they may use, once its arguments were parsed. Handlers should check `name` to see if it's one of theirs.
+#[derive(Debug, Event)]
+pub struct CommandEvent {
+    pub entity_id: EntityId,
+    /// The name the command was registered under, even if it was run through an alias.
+    pub name: String,
+    pub args: CommandArgs,
+}
+
+#[derive(Debug, Event)]
+pub struct PlayerDisconnectEvent(pub SessionId);
+
+/// Sent when a client goes over the rate limit of a packet type, once per run of excess packets.
+#[derive(Debug, Event)]
+pub struct PlayerFloodEvent {
+    /// [`None`] if the client hasn't identified yet.
+    pub entity_id: Option<EntityId>,
+    pub session: SessionId,
+    pub addr: SocketAddr,
+    pub packet_id: Byte,
+    /// Whether the client was kicked rather than having its packets dropped.
+    pub kicked: bool,
+}
+
+/// Saves the level to disk immediately.
+#[derive(Debug, Event)]
+pub struct SaveWorldEvent;
+
+/// Gives a player a rank, updating their client if they are online.
+#[derive(Debug, Event)]
+pub struct SetRankEvent {
+    pub username: String,
+    pub rank: String,
+    /// The player who asked for the change, told once it was made.
+    pub by: Option<EntityId>,
+}
+
+/// Sent once the rank given by a [`SetRankEvent`] was saved to the rank file.
+#[derive(Debug, Event)]
+pub struct RankSavedEvent {
+    pub username: String,
+    pub rank: String,
+    pub by: Option<EntityId>,
+}
+
+/// Sent after the level was saved to disk.
+#[derive(Debug, Event)]
+pub struct WorldSavedEvent {
+    pub path: String,
+}
+
+/// Sent once when the world starts running, before any client packet or tick reaches it. The listener may not be bound yet.
+#[derive(Debug, Event)]
+pub struct ServerStartEvent;
+
+/// Sent once while the server shuts down, after every client was told to disconnect and the level was saved.
+#[derive(Debug, Event)]
+pub struct ServerStopEvent {
+    pub reason: String,
+}
@@ -1,13 +1,288 @@
+use std::{collections::HashMap, mem, str::FromStr};
+
 use evenio::prelude::*;
-use tracing::info;
+use tracing::{debug, info, warn};
 
-use crate::{event::PlayerJoinEvent, world::ClientConnection};
+use crate::{
+    event::{CpeHandshakeEvent, ExtEntryEvent, ExtInfoEvent, PlayerJoinEvent},
+    networking::{
+        extension::{ExtEntryPacket, ExtInfoPacket, Int},
+        ClientPacketRegistry, PacketString, Short,
+    },
+    world::ClientConnection,
+    SOFTWARE_NAME,
+};
 
-pub fn add_cpe_handlers(world: &mut World) {
+pub fn add_cpe_handlers(world: &mut World, registry: ExtensionRegistry) {
+    world.add_handler(cpe_handshake_handler);
+    world.add_handler(ext_info_handler);
+    world.add_handler(ext_entry_handler);
     world.add_handler(on_player_join);
+
+    let extension_registry = world.spawn();
+    world.insert(extension_registry, registry);
+}
+
+/// Registers the handshake packets along with the packets of every extension in `extensions`.
+pub fn add_cpe_packets(registry: &mut ClientPacketRegistry, extensions: &ExtensionRegistry) {
+    registry.register::<ExtInfoPacket>();
+    registry.register::<ExtEntryPacket>();
+
+    for extension in extensions.iter() {
+        (extension.packet_registrar)(registry);
+    }
+}
+
+/// A CPE extension implemented by the server.
+///
+/// See <https://wiki.vg/Classic_Protocol_Extension> for the list of known extensions.
+pub trait CpeExtension {
+    const NAME: &'static str;
+    const VERSION: Int;
+
+    /// Registers the C2S packets introduced by the extension.
+    fn register_packets(_registry: &mut ClientPacketRegistry) {}
+
+    /// Decides which version of the extension to use given the version the client advertised. Returning [`None`] disables the extension for that client.
+    ///
+    /// By default, the extension is only enabled when the versions match exactly.
+    fn negotiate(client_version: Int) -> Option<Int> {
+        (client_version == Self::VERSION).then_some(Self::VERSION)
+    }
+}
+
+/// The extensions advertised to CPE clients during the handshake.
+#[derive(Component, Default, Debug)]
+pub struct ExtensionRegistry {
+    extensions: Vec<ExtensionRegistryEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtensionRegistryEntry {
+    name: &'static str,
+    version: Int,
+    packet_registrar: fn(&mut ClientPacketRegistry),
+    negotiator: fn(Int) -> Option<Int>,
+}
+
+impl ExtensionRegistry {
+    pub fn register<E: CpeExtension>(&mut self) {
+        assert!(
+            E::NAME.len() <= PacketString::LENGTH,
+            "Extension name {} is too long",
+            E::NAME
+        );
+
+        let entry = ExtensionRegistryEntry {
+            name: E::NAME,
+            version: E::VERSION,
+            packet_registrar: E::register_packets,
+            negotiator: E::negotiate,
+        };
+
+        match self.extensions.iter_mut().find(|ext| ext.name == E::NAME) {
+            Some(existing) => *existing = entry,
+            None => self.extensions.push(entry),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ExtensionRegistryEntry> {
+        self.extensions.iter().find(|ext| ext.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ExtensionRegistryEntry> {
+        self.extensions.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.extensions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.extensions.is_empty()
+    }
+}
+
+impl ExtensionRegistryEntry {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn version(&self) -> Int {
+        self.version
+    }
+
+    pub fn negotiate(&self, client_version: Int) -> Option<Int> {
+        (self.negotiator)(client_version)
+    }
+}
+
+/// Attached to a player's entity while the client's extension list is still being received.
+#[derive(Component, Debug)]
+struct CpeHandshake {
+    username: String,
+    app_name: Option<String>,
+    remaining: u16,
+    client_extensions: HashMap<String, Int>,
 }
 
-fn on_player_join(e: Receiver<PlayerJoinEvent>, connections: Fetcher<&ClientConnection>) {
-    info!("Player supports CPE: {}", e.event.cpe);
-    let player = connections.get(e.event.entity_id).unwrap();
+/// The set of extensions both the server and the client support, along with the negotiated versions.
+///
+/// Attached to every player's entity once the CPE handshake is complete.
+#[derive(Component, Debug)]
+pub struct CpeExtensions {
+    pub app_name: String,
+    extensions: HashMap<String, Int>,
+}
+
+impl CpeExtensions {
+    pub fn supports(&self, name: &str) -> bool {
+        self.extensions.contains_key(name)
+    }
+
+    pub fn version(&self, name: &str) -> Option<Int> {
+        self.extensions.get(name).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Int)> {
+        self.extensions
+            .iter()
+            .map(|(name, &version)| (name.as_str(), version))
+    }
+}
+
+type HandshakeSender<'a> =
+    Sender<'a, (PlayerJoinEvent, Insert<CpeExtensions>, Remove<CpeHandshake>)>;
+
+fn cpe_handshake_handler(
+    e: Receiver<CpeHandshakeEvent>,
+    connections: Fetcher<&ClientConnection>,
+    Single(registry): Single<&ExtensionRegistry>,
+    mut sender: Sender<Insert<CpeHandshake>>,
+) {
+    let Ok(connection) = connections.get(e.event.entity_id) else {
+        return;
+    };
+
+    debug!("Starting CPE handshake with {}", e.event.username);
+
+    sender.insert(
+        e.event.entity_id,
+        CpeHandshake {
+            username: e.event.username.clone(),
+            app_name: None,
+            remaining: 0,
+            client_extensions: HashMap::new(),
+        },
+    );
+
+    connection.sender.send(Box::new(ExtInfoPacket {
+        app_name: PacketString::from_str(SOFTWARE_NAME).unwrap(),
+        extension_count: registry.len() as Short,
+    }));
+
+    for extension in registry.iter() {
+        connection.sender.send(Box::new(ExtEntryPacket {
+            ext_name: PacketString::from_str(extension.name).unwrap(),
+            version: extension.version,
+        }));
+    }
+}
+
+fn ext_info_handler(
+    e: Receiver<ExtInfoEvent>,
+    mut handshakes: Fetcher<&mut CpeHandshake>,
+    Single(registry): Single<&ExtensionRegistry>,
+    sender: HandshakeSender,
+) {
+    let Ok(handshake) = handshakes.get_mut(e.event.entity_id) else {
+        warn!("Received ExtInfo outside of a CPE handshake");
+        return;
+    };
+
+    if handshake.app_name.is_some() {
+        warn!("Received duplicate ExtInfo from {}", handshake.username);
+        return;
+    }
+
+    handshake.app_name = Some(e.event.app_name.clone());
+    handshake.remaining = e.event.extension_count;
+
+    if handshake.remaining == 0 {
+        finish_handshake(e.event.entity_id, handshake, registry, sender);
+    }
+}
+
+fn ext_entry_handler(
+    e: Receiver<ExtEntryEvent>,
+    mut handshakes: Fetcher<&mut CpeHandshake>,
+    Single(registry): Single<&ExtensionRegistry>,
+    sender: HandshakeSender,
+) {
+    let Ok(handshake) = handshakes.get_mut(e.event.entity_id) else {
+        warn!("Received ExtEntry outside of a CPE handshake");
+        return;
+    };
+
+    if handshake.app_name.is_none() || handshake.remaining == 0 {
+        warn!("Received unexpected ExtEntry from {}", handshake.username);
+        return;
+    }
+
+    handshake
+        .client_extensions
+        .insert(e.event.ext_name.clone(), e.event.version);
+    handshake.remaining -= 1;
+
+    if handshake.remaining == 0 {
+        finish_handshake(e.event.entity_id, handshake, registry, sender);
+    }
+}
+
+/// An extension is only enabled when the client supports it and [`CpeExtension::negotiate`] accepts the client's version.
+fn finish_handshake(
+    entity_id: EntityId,
+    handshake: &mut CpeHandshake,
+    registry: &ExtensionRegistry,
+    mut sender: HandshakeSender,
+) {
+    let extensions = registry
+        .iter()
+        .filter_map(|extension| {
+            let client_version = *handshake.client_extensions.get(extension.name)?;
+            Some((
+                extension.name.to_string(),
+                extension.negotiate(client_version)?,
+            ))
+        })
+        .collect();
+
+    sender.insert(
+        entity_id,
+        CpeExtensions {
+            app_name: handshake.app_name.take().unwrap_or_default(),
+            extensions,
+        },
+    );
+    sender.remove::<CpeHandshake>(entity_id);
+    sender.send(PlayerJoinEvent {
+        entity_id,
+        username: mem::take(&mut handshake.username),
+        cpe: true,
+    });
+}
+
+fn on_player_join(e: Receiver<PlayerJoinEvent>, extensions: Fetcher<&CpeExtensions>) {
+    if !e.event.cpe {
+        return;
+    }
+
+    if let Ok(extensions) = extensions.get(e.event.entity_id) {
+        info!(
+            "{} joined using {} with extensions: {:?}",
+            e.event.username,
+            extensions.app_name,
+            extensions.iter().collect::<Vec<_>>()
+        );
+    }
 }
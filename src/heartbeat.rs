@@ -0,0 +1,280 @@
+use std::{
+    future::Future,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use evenio::prelude::*;
+use rand::{distr::Alphanumeric, RngExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::ServerConfig,
+    world::{Player, TickEvent},
+    SOFTWARE_NAME,
+};
+
+/// The heartbeat endpoint used by the ClassiCube server list.
+pub const CLASSICUBE_HEARTBEAT_URL: &str = "https://www.classicube.net/server/heartbeat/";
+
+const SALT_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// Whether the server announces itself to the server list at `url`. Off unless asked for.
+    pub enabled: bool,
+    pub url: String,
+    pub interval_secs: u64,
+    /// Whether the server is shown on the public server list.
+    pub public: bool,
+    /// Whether players have to prove their name with the key from the server list.
+    ///
+    /// Independent of `enabled`, but without the heartbeat no key is valid, so only players let in by `allow_unverified_lan` can join.
+    pub verify_names: bool,
+    /// Lets players connecting from loopback or private addresses skip name verification.
+    pub allow_unverified_lan: bool,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: CLASSICUBE_HEARTBEAT_URL.into(),
+            interval_secs: 45,
+            public: false,
+            verify_names: true,
+            allow_unverified_lan: false,
+        }
+    }
+}
+
+/// Checks usernames against the verification key sent in [`crate::networking::c2s::PlayerIdentPacket`].
+///
+/// Present in the world whenever [`HeartbeatConfig::verify_names`] is set, even if the heartbeat isn't sent.
+#[derive(Component, Debug)]
+pub struct NameVerifier {
+    salt: String,
+    allow_unverified_lan: bool,
+}
+
+impl NameVerifier {
+    pub fn new(salt: String, allow_unverified_lan: bool) -> Self {
+        Self {
+            salt,
+            allow_unverified_lan,
+        }
+    }
+
+    pub fn salt(&self) -> &str {
+        &self.salt
+    }
+
+    /// The key is expected to be the hex encoded `md5(salt + username)`.
+    pub fn verify(&self, username: &str, verification_key: &str, addr: IpAddr) -> bool {
+        if self.allow_unverified_lan && is_lan(addr) {
+            return true;
+        }
+
+        let expected = format!("{:x}", md5::compute(format!("{}{username}", self.salt)));
+        expected.eq_ignore_ascii_case(verification_key.trim())
+    }
+}
+
+fn is_lan(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_loopback() || addr.is_private() || addr.is_link_local(),
+        IpAddr::V6(addr) => {
+            addr.is_loopback()
+                || addr.is_unique_local()
+                || addr.is_unicast_link_local()
+                || addr.to_ipv4_mapped().is_some_and(|addr| {
+                    addr.is_loopback() || addr.is_private() || addr.is_link_local()
+                })
+        },
+    }
+}
+
+pub fn generate_salt() -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(SALT_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Component)]
+struct Heartbeat {
+    interval: Duration,
+    last_beat: Option<Instant>,
+    sender: mpsc::Sender<usize>,
+}
+
+/// Inserts a [`NameVerifier`] checking keys against `salt`.
+pub fn add_name_verifier(world: &mut World, config: &HeartbeatConfig, salt: String) {
+    let verifier = world.spawn();
+    world.insert(
+        verifier,
+        NameVerifier::new(salt, config.allow_unverified_lan),
+    );
+}
+
+/// Adds the heartbeat to the world, announcing `salt` to the server list.
+///
+/// The returned future performs the HTTP requests and must be spawned onto the runtime.
+pub fn add_heartbeat(
+    world: &mut World,
+    config: &ServerConfig,
+    salt: String,
+) -> impl Future<Output = ()> {
+    let (sender, receiver) = mpsc::channel(1);
+
+    let heartbeat = world.spawn();
+    world.insert(
+        heartbeat,
+        Heartbeat {
+            interval: Duration::from_secs(config.heartbeat.interval_secs),
+            last_beat: None,
+            sender,
+        },
+    );
+    world.add_handler(heartbeat_tick_handler);
+
+    run_heartbeat(config.clone(), salt, receiver)
+}
+
+fn heartbeat_tick_handler(
+    _: Receiver<TickEvent>,
+    Single(heartbeat): Single<&mut Heartbeat>,
+    players: Fetcher<&Player>,
+) {
+    if heartbeat
+        .last_beat
+        .is_some_and(|last_beat| last_beat.elapsed() < heartbeat.interval)
+    {
+        return;
+    }
+
+    heartbeat.last_beat = Some(Instant::now());
+
+    // A full channel means the previous heartbeat is still in flight
+    let _ = heartbeat.sender.try_send(players.iter().count());
+}
+
+async fn run_heartbeat(config: ServerConfig, salt: String, mut receiver: mpsc::Receiver<usize>) {
+    // A hung request would hold up every later heartbeat, as only one is in flight at a time
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.heartbeat.interval_secs))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Failed to create the heartbeat client: {e}");
+            return;
+        },
+    };
+    let mut server_url = None;
+
+    while let Some(users) = receiver.recv().await {
+        let params = [
+            ("name", config.server.name.clone()),
+            ("port", config.network.port().to_string()),
+            ("users", users.to_string()),
+            ("max", config.server.max_players.to_string()),
+            ("salt", salt.clone()),
+            (
+                "public",
+                if config.heartbeat.public {
+                    "True"
+                } else {
+                    "False"
+                }
+                .into(),
+            ),
+            ("software", SOFTWARE_NAME.into()),
+            ("version", "7".into()),
+        ];
+
+        let response = match client
+            .post(&config.heartbeat.url)
+            .form(&params)
+            .send()
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Failed to send heartbeat: {e}");
+                continue;
+            },
+        };
+
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+
+        if !status.is_success() {
+            warn!("Heartbeat rejected ({status}): {body}");
+            continue;
+        }
+
+        debug!("Heartbeat sent with {users} users");
+
+        if server_url.as_ref() != Some(&body) {
+            info!("Server URL: {body}");
+            server_url = Some(body);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REMOTE: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 7));
+
+    fn key(salt: &str, username: &str) -> String {
+        format!("{:x}", md5::compute(format!("{salt}{username}")))
+    }
+
+    #[test]
+    fn accepts_the_key_for_the_salt_and_name() {
+        let verifier = NameVerifier::new("salt".into(), false);
+
+        assert!(verifier.verify("alice", &key("salt", "alice"), REMOTE));
+        assert!(verifier.verify("alice", &key("salt", "alice").to_uppercase(), REMOTE));
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let verifier = NameVerifier::new("salt".into(), false);
+
+        assert!(!verifier.verify("alice", &key("other", "alice"), REMOTE));
+        assert!(!verifier.verify("alice", &key("salt", "bob"), REMOTE));
+        assert!(!verifier.verify("alice", "", REMOTE));
+    }
+
+    #[test]
+    fn lan_skips_verification_only_when_allowed() {
+        let lan: [IpAddr; 6] = [
+            "127.0.0.1".parse().unwrap(),
+            "192.168.1.2".parse().unwrap(),
+            "10.0.0.1".parse().unwrap(),
+            "::1".parse().unwrap(),
+            "fd12:3456::1".parse().unwrap(),
+            "::ffff:172.16.0.1".parse().unwrap(),
+        ];
+
+        for addr in lan {
+            assert!(NameVerifier::new("salt".into(), true).verify("alice", "", addr));
+            assert!(!NameVerifier::new("salt".into(), false).verify("alice", "", addr));
+        }
+
+        assert!(!NameVerifier::new("salt".into(), true).verify("alice", "", REMOTE));
+        assert!(!NameVerifier::new("salt".into(), true).verify(
+            "alice",
+            "",
+            "2001:db8::1".parse().unwrap()
+        ));
+    }
+}
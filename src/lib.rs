@@ -1,10 +1,17 @@
 #[macro_use]
 extern crate enum_primitive;
+// Lets code generated by vintage-macros refer to this crate by name from inside it too
+extern crate self as vintage;
 
+pub mod command;
+pub mod config;
 pub mod default;
 pub mod event;
 pub mod extension;
+pub mod heartbeat;
 pub mod networking;
+pub mod rank;
+pub mod server;
 pub mod util;
 pub mod world;
 
@@ -1,27 +1,15 @@
-use std::{sync::Arc, thread, time::Duration};
+use std::{io, thread};
 
 use anyhow::Result;
-use evenio::prelude::*;
-use glam::{uvec3, vec3};
-use tokio::sync::{broadcast, mpsc};
-use tracing::{error, info, Level};
+use clap::Parser;
+use tokio::sync::mpsc;
+use tracing::{warn, Level};
 use vintage::{
-    default::{self, config::PlayerSpawnLocation},
-    event::PlayerDisconnectEvent,
-    extension,
-    networking::{
-        listener::{self, ClientMessage},
-        ClientPacketRegistry,
-    },
-    util::add_periodic_saver,
-    world::{Block, BlockWorld, TickEvent},
+    config::{Args, ServerConfig},
+    event::SetRankEvent,
+    server::{Server, ServerCommand},
 };
 
-enum WorldEvent {
-    Tick,
-    ClientMessage(ClientMessage),
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt()
@@ -29,90 +17,50 @@ async fn main() -> Result<()> {
         .with_max_level(Level::DEBUG)
         .init();
 
-    info!("Starting");
-
-    let mut world = World::new();
-
-    let player_spawn_location = world.spawn();
-    world.insert(
-        player_spawn_location,
-        PlayerSpawnLocation {
-            position: vec3(16.0, 34.0, 16.0),
-            pitch: 0.,
-            yaw: 0.,
-        },
-    );
-
-    let block_world = world.spawn();
-    world.insert(
-        block_world,
-        BlockWorld::new_or_load_from_file("./level.bin", uvec3(128, 64, 128), |dims, world| {
-            for x in 0..dims.x {
-                for y in 0..31 {
-                    for z in 0..dims.z {
-                        world.set_block(uvec3(x, y, z), Block::Dirt);
-                    }
-                }
-            }
-
-            for x in 0..dims.x {
-                for z in 0..dims.z {
-                    world.set_block(uvec3(x, 32, z), Block::GrassBlock);
-                }
-            }
-        }),
-    );
+    let config = ServerConfig::load(&Args::parse())?;
 
-    let (tx, mut rx) = mpsc::channel(32);
-    let (broadcast_tx, _) = broadcast::channel(32);
-    let broadcast_tx = Arc::new(broadcast_tx);
-
-    let mut packet_registry = ClientPacketRegistry::default();
-    default::add_default_packets(&mut packet_registry);
-
-    default::add_default_handlers(&mut world, broadcast_tx.clone());
-    add_periodic_saver(&mut world, Duration::from_secs(60), "./level.bin");
-    extension::add_cpe_handlers(&mut world);
-
-    tokio::spawn(listener::listen(
-        "127.0.0.1:8080",
-        tx,
-        broadcast_tx,
-        packet_registry,
-    ));
-    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    let server = Server::builder(config).build()?;
+    let commands = server.handle().commands();
+    // Not joined, as reading stdin can't be interrupted
+    thread::Builder::new()
+        .name("console".into())
+        .spawn(move || console(commands))?;
 
-    let (world_tx, mut world_rx) = mpsc::channel::<WorldEvent>(32);
+    server.run().await
+}
 
-    thread::Builder::new()
-        .name("world".into())
-        .spawn(move || {
-            while let Some(event) = world_rx.blocking_recv() {
-                match event {
-                    WorldEvent::Tick => {
-                        world.send(TickEvent {});
-                    },
-                    WorldEvent::ClientMessage(message) => match message {
-                        ClientMessage::Packet(packet) => {
-                            if let Err(e) = packet.exec(&mut world) {
-                                error!("failed to execute packet handler: {e}")
-                            }
-                        },
-                        ClientMessage::Disconnect(addr) => world.send(PlayerDisconnectEvent(addr)),
-                    },
-                }
-            }
-        })
-        .unwrap();
+/// Reads commands from stdin. `stop [reason]` shuts the server down and `rank <player> <rank>` gives a player a rank.
+fn console(commands: mpsc::Sender<ServerCommand>) {
+    for line in io::stdin().lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let line = line.trim();
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
 
-    loop {
-        tokio::select! {
-            Some(packet) = rx.recv() => {
-                world_tx.send(WorldEvent::ClientMessage(packet)).await?;
-            }
-            _ = interval.tick() => {
-                world_tx.send(WorldEvent::Tick).await?;
-            }
+        match command {
+            "" => {},
+            "stop" => {
+                let reason = args.trim();
+                let reason = (!reason.is_empty()).then(|| reason.to_owned());
+                let _ = commands.blocking_send(ServerCommand::Shutdown(reason));
+                return;
+            },
+            "rank" => {
+                let Some((username, rank)) = args.trim().split_once(' ') else {
+                    warn!("Usage: rank <player> <rank>");
+                    continue;
+                };
+                let event = SetRankEvent {
+                    username: username.to_owned(),
+                    rank: rank.trim().to_owned(),
+                    by: None,
+                };
+                let _ = commands.blocking_send(ServerCommand::Exec(Box::new(move |world| {
+                    world.send(event)
+                })));
+            },
+            _ => warn!("Unknown console command: {command}"),
         }
     }
 }
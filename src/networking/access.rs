@@ -0,0 +1,508 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+use anyhow::{ensure, Context, Result};
+use evenio::component::Component;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// An IP address or CIDR block, such as `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self> {
+        let addr = addr.to_canonical();
+        let max_prefix = max_prefix(addr);
+        ensure!(
+            prefix <= max_prefix,
+            "Prefix /{prefix} is too long for {addr}"
+        );
+
+        // Only the network part is kept, so equal ranges compare equal
+        let addr = match addr {
+            IpAddr::V4(v4) => IpAddr::V4((u32::from(v4) & v4_mask(prefix)).into()),
+            IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & v6_mask(prefix)).into()),
+        };
+
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                u32::from(ip) & v4_mask(self.prefix) == u32::from(net)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                u128::from(ip) & v6_mask(self.prefix) == u128::from(net)
+            },
+            _ => false,
+        }
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn v6_mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(addr: IpAddr) -> Self {
+        let addr = addr.to_canonical();
+        Self {
+            addr,
+            prefix: max_prefix(addr),
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((addr, prefix)) = s.split_once('/') else {
+            let addr = s
+                .parse::<IpAddr>()
+                .with_context(|| format!("Invalid IP address {s}"))?;
+            return Ok(addr.into());
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .with_context(|| format!("Invalid IP address {addr}"))?;
+        let prefix = prefix
+            .parse::<u8>()
+            .with_context(|| format!("Invalid prefix length {prefix}"))?;
+
+        Self::new(addr, prefix)
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(range: IpRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix == max_prefix(self.addr) {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ban {
+    pub ip: IpRange,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BanFile {
+    bans: Vec<Ban>,
+}
+
+/// IP bans that are checked before connections are handled, saved to a file whenever they change.
+///
+/// Clones share the same list, so it can be edited through any of them while the server runs.
+#[derive(Debug, Clone, Component)]
+pub struct BanList {
+    path: Option<PathBuf>,
+    bans: Arc<RwLock<Vec<Ban>>>,
+}
+
+impl BanList {
+    /// A ban list that is only kept in memory.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            bans: Arc::default(),
+        }
+    }
+
+    /// Loads the ban list from `path`, starting empty if it doesn't exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bans = if path.exists() {
+            let data = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read bans from {}", path.display()))?;
+            toml::from_str::<BanFile>(&data)
+                .with_context(|| format!("Invalid ban list in {}", path.display()))?
+                .bans
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            path: Some(path.to_owned()),
+            bans: Arc::new(RwLock::new(bans)),
+        })
+    }
+
+    /// Returns the reason of the first ban covering `ip`.
+    pub fn check(&self, ip: IpAddr) -> Option<String> {
+        self.bans
+            .read()
+            .unwrap()
+            .iter()
+            .find(|ban| ban.ip.contains(ip))
+            .map(|ban| ban.reason.clone())
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.read().unwrap().clone()
+    }
+
+    /// Bans `ip`, replacing the reason if it is already banned.
+    pub fn ban(&self, ip: IpRange, reason: impl Into<String>) -> Result<()> {
+        let reason = reason.into();
+        let mut bans = self.bans.write().unwrap();
+
+        match bans.iter_mut().find(|ban| ban.ip == ip) {
+            Some(ban) => ban.reason = reason,
+            None => bans.push(Ban { ip, reason }),
+        }
+
+        info!("Banned {ip}");
+        self.save(&bans)
+    }
+
+    /// Returns whether `ip` was banned. Only removes exact matches, not bans of ranges containing it.
+    pub fn unban(&self, ip: IpRange) -> Result<bool> {
+        let mut bans = self.bans.write().unwrap();
+        let len = bans.len();
+        bans.retain(|ban| ban.ip != ip);
+
+        if bans.len() == len {
+            return Ok(false);
+        }
+
+        info!("Unbanned {ip}");
+        self.save(&bans)?;
+
+        Ok(true)
+    }
+
+    fn save(&self, bans: &[Ban]) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let data = toml::to_string_pretty(&BanFile {
+            bans: bans.to_vec(),
+        })?;
+        fs::write(path, data).with_context(|| format!("Failed to save bans to {}", path.display()))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// How many connections a single IP may have open at once. 0 disables the limit.
+    pub max_connections_per_ip: usize,
+    /// How many new connections an IP may open per minute, once its burst is used up. 0 disables the limit.
+    pub connects_per_minute: u32,
+    pub connect_burst: u32,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_ip: 5,
+            connects_per_minute: 30,
+            connect_burst: 10,
+        }
+    }
+}
+
+/// Why a connection was turned away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    /// Connecting too often. Not worth replying to.
+    Throttled,
+    TooManyConnections,
+    Banned(String),
+}
+
+impl Rejection {
+    /// The disconnect reason shown to the client, if it gets one.
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            Rejection::Throttled => None,
+            Rejection::TooManyConnections => Some("Too many connections from your IP"),
+            Rejection::Banned(reason) => Some(reason),
+        }
+    }
+}
+
+/// Decides which connections are accepted, before any work is done for them.
+#[derive(Debug)]
+pub struct AccessControl {
+    throttle: ThrottleConfig,
+    bans: BanList,
+    clients: Arc<Mutex<HashMap<IpAddr, IpState>>>,
+}
+
+#[derive(Debug)]
+struct IpState {
+    connections: usize,
+    tokens: f32,
+    last_refill: Instant,
+}
+
+/// Counts towards the connection limit of an IP until dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    ip: IpAddr,
+    clients: Arc<Mutex<HashMap<IpAddr, IpState>>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(state) = self.clients.lock().unwrap().get_mut(&self.ip) {
+            state.connections -= 1;
+        }
+    }
+}
+
+/// Once this many IPs are tracked, those without open connections and with a full bucket are forgotten.
+const PRUNE_THRESHOLD: usize = 1024;
+
+impl AccessControl {
+    pub fn new(throttle: ThrottleConfig, bans: BanList) -> Self {
+        Self {
+            throttle,
+            bans,
+            clients: Arc::default(),
+        }
+    }
+
+    pub fn bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    /// Bans are checked first, so that banned clients are always told why.
+    pub fn admit(&self, ip: IpAddr) -> Result<ConnectionPermit, Rejection> {
+        let ip = ip.to_canonical();
+
+        if let Some(reason) = self.bans.check(ip) {
+            return Err(Rejection::Banned(reason));
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+
+        if clients.len() >= PRUNE_THRESHOLD {
+            let burst = self.throttle.connect_burst as f32;
+            let rate = self.throttle.connects_per_minute as f32 / 60.;
+            clients.retain(|_, state| {
+                let tokens = state.tokens + state.last_refill.elapsed().as_secs_f32() * rate;
+                state.connections > 0 || tokens < burst
+            });
+        }
+
+        let state = clients.entry(ip).or_insert(IpState {
+            connections: 0,
+            tokens: self.throttle.connect_burst as f32,
+            last_refill: Instant::now(),
+        });
+
+        if self.throttle.connects_per_minute > 0 {
+            let rate = self.throttle.connects_per_minute as f32 / 60.;
+            state.tokens = (state.tokens + state.last_refill.elapsed().as_secs_f32() * rate)
+                .min(self.throttle.connect_burst as f32);
+            state.last_refill = Instant::now();
+
+            if state.tokens < 1. {
+                return Err(Rejection::Throttled);
+            }
+            state.tokens -= 1.;
+        }
+
+        if self.throttle.max_connections_per_ip > 0
+            && state.connections >= self.throttle.max_connections_per_ip
+        {
+            return Err(Rejection::TooManyConnections);
+        }
+
+        state.connections += 1;
+
+        Ok(ConnectionPermit {
+            ip,
+            clients: self.clients.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(range("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(range("10.1.2.3").to_string(), "10.1.2.3");
+        assert_eq!(range("10.1.2.3/32").to_string(), "10.1.2.3");
+        assert_eq!(range("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert_eq!(range("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert_eq!(range("2001:db8::1").to_string(), "2001:db8::1");
+        assert_eq!(range("10.0.0.0/8"), range("10.255.0.0/8"));
+    }
+
+    #[test]
+    fn v4_mapped_ranges_are_v4() {
+        assert_eq!(range("::ffff:10.1.2.3"), range("10.1.2.3"));
+        assert_eq!(range("::ffff:10.1.2.3/8"), range("10.0.0.0/8"));
+        assert!(range("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        for bad in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/8/8",
+            "10.0.0",
+            "",
+        ] {
+            assert!(bad.parse::<IpRange>().is_err(), "{bad} was accepted");
+        }
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let lan = range("192.168.0.0/16");
+        assert!(lan.contains(ip("192.168.0.1")));
+        assert!(lan.contains(ip("192.168.255.255")));
+        assert!(!lan.contains(ip("192.169.0.1")));
+        assert!(!lan.contains(ip("::ffff:192.169.0.1")));
+        assert!(!lan.contains(ip("2001:db8::1")));
+
+        let v6 = range("2001:db8::/32");
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(range("::/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ban_list_finds_the_first_ban() {
+        let bans = BanList::in_memory();
+        bans.ban(range("10.0.0.0/8"), "range").unwrap();
+        bans.ban(range("10.1.2.3"), "single").unwrap();
+
+        assert_eq!(bans.check(ip("10.1.2.3")).as_deref(), Some("range"));
+        assert_eq!(bans.check(ip("11.0.0.1")), None);
+
+        bans.ban(range("10.0.0.0/8"), "changed").unwrap();
+        assert_eq!(bans.bans().len(), 2);
+        assert_eq!(bans.check(ip("10.1.2.3")).as_deref(), Some("changed"));
+
+        // Only exact matches are removed
+        assert!(!bans.unban(range("10.1.0.0/16")).unwrap());
+        assert!(bans.unban(range("10.0.0.0/8")).unwrap());
+        assert_eq!(bans.check(ip("10.1.2.3")).as_deref(), Some("single"));
+    }
+
+    fn throttle(max_connections_per_ip: usize, connect_burst: u32) -> ThrottleConfig {
+        ThrottleConfig {
+            max_connections_per_ip,
+            connects_per_minute: 1,
+            connect_burst,
+        }
+    }
+
+    #[test]
+    fn admit_limits_open_connections() {
+        let access = AccessControl::new(throttle(2, 10), BanList::in_memory());
+
+        let first = access.admit(ip("10.0.0.1")).unwrap();
+        let _second = access.admit(ip("10.0.0.1")).unwrap();
+        assert_eq!(
+            access.admit(ip("10.0.0.1")).unwrap_err(),
+            Rejection::TooManyConnections
+        );
+        assert!(access.admit(ip("10.0.0.2")).is_ok());
+
+        drop(first);
+        assert!(access.admit(ip("10.0.0.1")).is_ok());
+    }
+
+    #[test]
+    fn admit_throttles_reconnects() {
+        let access = AccessControl::new(throttle(0, 2), BanList::in_memory());
+
+        assert!(access.admit(ip("10.0.0.1")).is_ok());
+        assert!(access.admit(ip("::ffff:10.0.0.1")).is_ok());
+        assert_eq!(
+            access.admit(ip("10.0.0.1")).unwrap_err(),
+            Rejection::Throttled
+        );
+    }
+
+    #[test]
+    fn admit_checks_bans_first() {
+        let bans = BanList::in_memory();
+        bans.ban(range("10.0.0.1"), "Go away").unwrap();
+        let access = AccessControl::new(throttle(1, 1), bans);
+
+        // Even once throttled, banned clients are told why
+        for _ in 0..3 {
+            let rejection = access.admit(ip("10.0.0.1")).unwrap_err();
+            assert_eq!(rejection, Rejection::Banned("Go away".into()));
+            assert_eq!(rejection.reason(), Some("Go away"));
+        }
+
+        let _permit = access.admit(ip("10.0.0.2")).unwrap();
+        assert_eq!(
+            access.admit(ip("10.0.0.2")).unwrap_err(),
+            Rejection::Throttled
+        );
+    }
+}
@@ -1,23 +1,24 @@
 use anyhow::{Context, Result};
-use enum_primitive::FromPrimitive;
-use glam::{uvec3, vec3};
+use glam::{ivec3, vec3};
 use std::{
+    any::Any,
     fmt::Debug,
     io::{Cursor, Read},
 };
 
 use byteorder::{BigEndian, ReadBytesExt};
 use evenio::world::World;
-use tracing::warn;
 
 use crate::{
-    event::{PlayerJoinEvent, PlayerMessageEvent, PlayerMoveEvent, SetBlockEvent},
-    world::{self, ClientConnection, Rotation},
+    event::{PlayerIdentEvent, PlayerSetBlockEvent, PrePlayerMessageEvent, PrePlayerMoveEvent},
+    world::{ClientConnection, Rotation},
 };
 
 use super::{
-    extension::Int, listener::ClientInfo, util::angle_to_f32, Byte, FByte, FShort, PacketString,
-    SByte, Short,
+    extension::{Int, CPE_MAGIC},
+    listener::ClientInfo,
+    util::angle_to_f32,
+    Byte, ClassicPacket, FByte, FShort, PacketString, SByte, Short,
 };
 
 pub struct PacketReader {
@@ -72,10 +73,17 @@ impl PacketReader {
     }
 }
 
-pub trait C2SPacket: Send + Sync + Debug {
+pub trait C2SPacket: Send + Sync + Debug + Any {
     fn exec(&self, world: &mut World, client_info: &ClientInfo) -> Result<()>;
 }
 
+impl dyn C2SPacket {
+    /// Returns the packet as a `P` if that is what it is.
+    pub fn downcast_ref<P: C2SPacket>(&self) -> Option<&P> {
+        (self as &dyn Any).downcast_ref()
+    }
+}
+
 pub trait C2SPacketEntry {
     const ID: Byte;
     const SIZE: usize;
@@ -83,104 +91,117 @@ pub trait C2SPacketEntry {
     fn deserialise(reader: &mut PacketReader) -> Result<Box<dyn C2SPacket>>;
 }
 
-/// Sent by a player joining a server with relevant information. The protocol version is 0x07, unless you're using a client below 0.28.
-#[derive(Debug)]
+/// Sent by a player joining a server with relevant information. The protocol version is 0x07; older clients send a [`LegacyPlayerIdentPacket`] instead.
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x00, c2s)]
 pub struct PlayerIdentPacket {
-    protocol_version: Byte,
-    username: PacketString,
-    #[allow(unused)]
-    verification_key: PacketString,
-    padding: Byte,
-}
-
-impl C2SPacketEntry for PlayerIdentPacket {
-    const ID: Byte = 0x00;
-    const SIZE: usize = 1 + 2 * PacketString::LENGTH + 1;
-
-    fn deserialise(reader: &mut PacketReader) -> Result<Box<dyn C2SPacket>> {
-        let protocol_version = reader.read_byte()?;
-        let username = reader.read_packet_string()?;
-        let verification_key = reader.read_packet_string()?;
-        let padding = reader.read_byte()?;
-
-        Ok(Box::new(Self {
-            protocol_version,
-            username,
-            verification_key,
-            padding,
-        }))
-    }
+    pub protocol_version: Byte,
+    pub username: PacketString,
+    pub verification_key: PacketString,
+    pub padding: Byte,
 }
 
 impl C2SPacket for PlayerIdentPacket {
     fn exec(&self, world: &mut World, client_info: &ClientInfo) -> Result<()> {
-        if self.protocol_version < 7 {
-            warn!("Client's protocol version in less than 7")
-        }
+        identify(
+            world,
+            client_info,
+            self.protocol_version,
+            &self.username,
+            &self.verification_key,
+            self.padding == CPE_MAGIC,
+        );
 
-        let player = world.spawn();
+        Ok(())
+    }
+}
 
-        *client_info.player_id.lock().unwrap() = Some(player);
+/// The [`PlayerIdentPacket`] of protocol versions 3 to 6, which has no padding byte and so no way to ask for CPE.
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x00, c2s)]
+pub struct LegacyPlayerIdentPacket {
+    pub protocol_version: Byte,
+    pub username: PacketString,
+    pub verification_key: PacketString,
+}
 
-        world.insert(
-            player,
-            ClientConnection {
-                sender: client_info.packet_sender.clone(),
-                addr: client_info.addr,
-            },
+impl C2SPacket for LegacyPlayerIdentPacket {
+    fn exec(&self, world: &mut World, client_info: &ClientInfo) -> Result<()> {
+        identify(
+            world,
+            client_info,
+            self.protocol_version,
+            &self.username,
+            &self.verification_key,
+            false,
         );
 
-        world.send(PlayerJoinEvent {
-            entity_id: player,
-            username: self.username.to_string(),
-            cpe: self.padding == 0x42,
-        });
-
         Ok(())
     }
 }
 
+fn identify(
+    world: &mut World,
+    client_info: &ClientInfo,
+    protocol_version: Byte,
+    username: &PacketString,
+    verification_key: &PacketString,
+    cpe: bool,
+) {
+    let mut player_id = client_info.player_id.lock().unwrap();
+
+    // A second entity would be left behind as a ghost player holding a slot
+    if player_id.is_some() {
+        client_info.packet_sender.kick("Already identified");
+        return;
+    }
+
+    let player = world.spawn();
+    *player_id = Some(player);
+    drop(player_id);
+
+    world.insert(
+        player,
+        ClientConnection {
+            session: client_info.session,
+            sender: client_info.packet_sender.clone(),
+            addr: client_info.addr,
+            activity: client_info.activity.clone(),
+            protocol_version,
+        },
+    );
+
+    world.send(PlayerIdentEvent {
+        entity_id: player,
+        username: username.to_string(),
+        verification_key: verification_key.to_string(),
+        cpe,
+    });
+}
+
 /// Sent when a user changes a block. The mode field indicates whether a block was created (0x01) or destroyed (0x00).
 ///
 /// Block type is always the type player is holding, even when destroying.
 ///
 /// Client assumes that this command packet always succeeds, and so draws the new block immediately. To disallow block creation, server must send back Set Block packet with the old block type.
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x05, c2s)]
 pub struct SetBlockPacket {
-    x: Short,
-    y: Short,
-    z: Short,
-    mode: Byte,
-    block_type: Byte,
-}
-
-impl C2SPacketEntry for SetBlockPacket {
-    const ID: Byte = 0x05;
-    const SIZE: usize = 3 * 2 + 2;
-
-    fn deserialise(reader: &mut PacketReader) -> Result<Box<dyn C2SPacket>> {
-        let x = reader.read_short()?;
-        let y = reader.read_short()?;
-        let z = reader.read_short()?;
-        let mode = reader.read_byte()?;
-        let block_type = reader.read_byte()?;
-
-        Ok(Box::new(Self {
-            x,
-            y,
-            z,
-            mode,
-            block_type,
-        }))
-    }
+    pub x: Short,
+    pub y: Short,
+    pub z: Short,
+    pub mode: Byte,
+    pub block_type: Byte,
 }
 
 impl C2SPacket for SetBlockPacket {
-    fn exec(&self, world: &mut World, _client_info: &ClientInfo) -> Result<()> {
-        world.send(SetBlockEvent {
-            block: world::Block::from_u8(self.block_type).context("Invalid block id")?,
+    fn exec(&self, world: &mut World, client_info: &ClientInfo) -> Result<()> {
+        world.send(PlayerSetBlockEvent {
+            entity_id: (*client_info.player_id.lock().unwrap())
+                .context("SetBlock sent before PlayerIdent")?,
+            pos: ivec3(self.x as i32, self.y as i32, self.z as i32),
             placed: self.mode == 1,
-            pos: uvec3(self.x as u32, self.y as u32, self.z as u32),
+            block_id: self.block_type,
         });
 
         Ok(())
@@ -188,84 +209,81 @@ impl C2SPacket for SetBlockPacket {
 }
 
 /// Sent frequently (even while not moving) by the player with the player's current location and orientation. Player ID is always -1 (255), referring to itself.
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x08, c2s)]
 pub struct PositionPacket {
-    #[allow(unused)]
-    player_id: SByte,
-    x: FShort,
-    y: FShort,
-    z: FShort,
-    yaw: Byte,
-    pitch: Byte,
-}
-
-impl C2SPacketEntry for PositionPacket {
-    const ID: Byte = 0x08;
-    const SIZE: usize = 1 + 3 * 2 + 2;
-
-    fn deserialise(reader: &mut PacketReader) -> Result<Box<dyn C2SPacket>> {
-        let player_id = reader.read_sbyte()?;
-        let x = reader.read_fshort()?;
-        let y = reader.read_fshort()?;
-        let z = reader.read_fshort()?;
-        let yaw = reader.read_byte()?;
-        let pitch = reader.read_byte()?;
-
-        Ok(Box::new(Self {
-            player_id,
-            x,
-            y,
-            z,
-            yaw,
-            pitch,
-        }))
-    }
+    pub player_id: SByte,
+    pub x: FShort,
+    pub y: FShort,
+    pub z: FShort,
+    pub yaw: Byte,
+    pub pitch: Byte,
 }
 
 impl C2SPacket for PositionPacket {
     fn exec(&self, world: &mut World, client_info: &ClientInfo) -> Result<()> {
-        let entity_id = client_info.player_id.lock().unwrap();
+        let entity_id =
+            (*client_info.player_id.lock().unwrap()).context("Position sent before PlayerIdent")?;
 
-        world.send(PlayerMoveEvent {
-            pos: vec3(self.x.into(), self.y.into(), self.z.into()),
-            rot: Rotation {
+        world.send(PrePlayerMoveEvent::new(
+            entity_id,
+            vec3(self.x.into(), self.y.into(), self.z.into()),
+            Rotation {
                 pitch: angle_to_f32(self.pitch),
                 yaw: angle_to_f32(self.yaw),
             },
-            entity_id: entity_id.unwrap(),
-        });
+        ));
 
         Ok(())
     }
 }
 
 /// Contain chat messages sent by player. Player ID is always -1 (255), referring to itself.
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x0d, c2s)]
 pub struct MessagePacket {
-    #[allow(unused)]
-    player_id: SByte,
-    message: PacketString,
+    pub player_id: SByte,
+    pub message: PacketString,
 }
 
-impl C2SPacketEntry for MessagePacket {
-    const ID: Byte = 0x0d;
-    const SIZE: usize = 1 + PacketString::LENGTH;
-
-    fn deserialise(reader: &mut PacketReader) -> Result<Box<dyn C2SPacket>> {
-        let player_id = reader.read_sbyte()?;
-        let message = reader.read_packet_string()?;
+impl C2SPacket for MessagePacket {
+    fn exec(&self, world: &mut World, client_info: &ClientInfo) -> Result<()> {
+        world.send(PrePlayerMessageEvent::new(
+            (*client_info.player_id.lock().unwrap()).context("Message sent before PlayerIdent")?,
+            self.message.to_string(),
+        ));
 
-        Ok(Box::new(Self { player_id, message }))
+        Ok(())
     }
 }
 
-impl C2SPacket for MessagePacket {
-    fn exec(&self, world: &mut World, client_info: &ClientInfo) -> Result<()> {
-        world.send(PlayerMessageEvent {
-            entity_id: (*client_info.player_id.lock().unwrap()).unwrap(),
-            message: self.message.to_string(),
-        });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::extension::{ExtEntryPacket, ExtInfoPacket};
+
+    #[test]
+    fn packet_sizes() {
+        // Without the packet ID, as in the protocol documentation
+        assert_eq!(PlayerIdentPacket::SIZE, 130);
+        assert_eq!(LegacyPlayerIdentPacket::SIZE, 129);
+        assert_eq!(SetBlockPacket::SIZE, 8);
+        assert_eq!(PositionPacket::SIZE, 9);
+        assert_eq!(MessagePacket::SIZE, 65);
+        assert_eq!(ExtInfoPacket::SIZE, 66);
+        assert_eq!(ExtEntryPacket::SIZE, 68);
+    }
 
-        Ok(())
+    #[test]
+    fn reads_fields_in_order() {
+        let mut data = vec![0x00, 0x10, 0xff, 0xfe, 0x00, 0x02, 0x01, 0x31];
+        data.extend_from_slice(b"trailing");
+
+        let packet = SetBlockPacket::deserialise(&mut PacketReader::new(data)).unwrap();
+        let packet = packet.downcast_ref::<SetBlockPacket>().unwrap();
+        assert_eq!((packet.x, packet.y, packet.z), (16, -2, 2));
+        assert_eq!((packet.mode, packet.block_type), (1, 0x31));
+
+        assert!(SetBlockPacket::deserialise(&mut PacketReader::new(vec![0; 7])).is_err());
     }
 }
@@ -1,85 +1,51 @@
-use super::{
-    c2s::{C2SPacket, C2SPacketEntry, PacketReader},
-    listener::ClientInfo,
-    s2c::{PacketWriter, S2CPacket},
-    Byte, PacketString, Short,
-};
-use anyhow::Result;
+use super::{c2s::C2SPacket, listener::ClientInfo, Byte, ClassicPacket, PacketString, Short};
+use crate::event::{ExtEntryEvent, ExtInfoEvent};
+use anyhow::{Context, Result};
 use evenio::world::World;
 
 pub mod s2c;
 
 pub type Int = i32;
 
-#[derive(Debug)]
+/// Value of the [`super::c2s::PlayerIdentPacket`] padding byte sent by clients that support CPE.
+pub const CPE_MAGIC: Byte = 0x42;
+
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x10, c2s, s2c, min_version = 7)]
 pub struct ExtInfoPacket {
     pub app_name: PacketString,
     pub extension_count: Short,
 }
 
-impl S2CPacket for ExtInfoPacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_packet_string(&self.app_name)?;
-        writer.write_short(self.extension_count)
-    }
-
-    fn id(&self) -> u8 {
-        0x10
-    }
-}
-
 impl C2SPacket for ExtInfoPacket {
     fn exec(&self, world: &mut World, client_info: &ClientInfo) -> Result<()> {
-        todo!()
-    }
-}
-
-impl C2SPacketEntry for ExtInfoPacket {
-    const ID: Byte = 0x10;
-    const SIZE: usize = 67;
-
-    fn deserialise(reader: &mut PacketReader) -> Result<Box<dyn C2SPacket>> {
-        let app_name = reader.read_packet_string()?;
-        let extension_count = reader.read_short()?;
+        world.send(ExtInfoEvent {
+            entity_id: (*client_info.player_id.lock().unwrap())
+                .context("ExtInfo sent before PlayerIdent")?,
+            app_name: self.app_name.to_string(),
+            extension_count: self.extension_count.max(0) as u16,
+        });
 
-        Ok(Box::new(ExtInfoPacket {
-            app_name,
-            extension_count,
-        }))
+        Ok(())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x11, c2s, s2c, min_version = 7)]
 pub struct ExtEntryPacket {
     pub ext_name: PacketString,
     pub version: Int,
 }
 
-impl S2CPacket for ExtEntryPacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_packet_string(&self.ext_name)?;
-        writer.write_int(self.version)
-    }
-
-    fn id(&self) -> u8 {
-        0x11
-    }
-}
-
 impl C2SPacket for ExtEntryPacket {
     fn exec(&self, world: &mut World, client_info: &ClientInfo) -> Result<()> {
-        todo!()
-    }
-}
-
-impl C2SPacketEntry for ExtEntryPacket {
-    const ID: Byte = 0x11;
-    const SIZE: usize = 69;
-
-    fn deserialise(reader: &mut PacketReader) -> Result<Box<dyn C2SPacket>> {
-        let ext_name = reader.read_packet_string()?;
-        let version = reader.read_int()?;
-
-        Ok(Box::new(ExtEntryPacket { ext_name, version }))
+        world.send(ExtEntryEvent {
+            entity_id: (*client_info.player_id.lock().unwrap())
+                .context("ExtEntry sent before PlayerIdent")?,
+            ext_name: self.ext_name.to_string(),
+            version: self.version,
+        });
+
+        Ok(())
     }
 }
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+use super::{c2s::C2SPacket, listener::ClientInfo, s2c::S2CPacket};
+
+/// What happens to a packet once an [`Interceptor`] has seen it.
+pub enum Verdict<P: ?Sized> {
+    /// Hands the packet on unchanged.
+    Pass,
+    /// Discards the packet. Interceptors later in the chain don't see it.
+    Drop,
+    /// Hands on this packet in its place.
+    Replace(Box<P>),
+}
+
+/// Sees the raw packets of every connection, inbound ones before they reach the world and outbound ones just before they are written.
+///
+/// Runs on the connection's own task, so it must not block.
+pub trait Interceptor: Send + Sync {
+    fn on_inbound(&self, _client: &ClientInfo, _packet: &dyn C2SPacket) -> Verdict<dyn C2SPacket> {
+        Verdict::Pass
+    }
+
+    /// The connection is closed after a [`super::s2c::DisconnectPlayerPacket`] even if it is dropped or replaced.
+    fn on_outbound(&self, _client: &ClientInfo, _packet: &dyn S2CPacket) -> Verdict<dyn S2CPacket> {
+        Verdict::Pass
+    }
+}
+
+/// [`Interceptor`]s in the order they run in.
+#[derive(Default, Clone)]
+pub struct InterceptorChain {
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl InterceptorChain {
+    pub fn add<I: Interceptor + 'static>(&mut self, interceptor: I) {
+        self.interceptors.push(Arc::new(interceptor));
+    }
+
+    pub fn len(&self) -> usize {
+        self.interceptors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.interceptors.is_empty()
+    }
+
+    /// Returns [`None`] if the packet was dropped.
+    pub fn inbound(
+        &self,
+        client: &ClientInfo,
+        mut packet: Box<dyn C2SPacket>,
+    ) -> Option<Box<dyn C2SPacket>> {
+        for interceptor in &self.interceptors {
+            match interceptor.on_inbound(client, packet.as_ref()) {
+                Verdict::Pass => {},
+                Verdict::Drop => return None,
+                Verdict::Replace(replacement) => packet = replacement,
+            }
+        }
+
+        Some(packet)
+    }
+
+    /// Returns [`None`] if the packet was dropped.
+    pub fn outbound(
+        &self,
+        client: &ClientInfo,
+        mut packet: Arc<dyn S2CPacket>,
+    ) -> Option<Arc<dyn S2CPacket>> {
+        for interceptor in &self.interceptors {
+            match interceptor.on_outbound(client, packet.as_ref()) {
+                Verdict::Pass => {},
+                Verdict::Drop => return None,
+                Verdict::Replace(replacement) => packet = replacement.into(),
+            }
+        }
+
+        Some(packet)
+    }
+}
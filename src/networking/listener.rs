@@ -1,30 +1,114 @@
 use std::{
+    collections::HashMap,
+    fmt::{self, Display},
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
 use evenio::{entity::EntityId, world::World};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
-    sync::{broadcast, mpsc},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, Notify,
+    },
 };
-use tracing::{info, trace, warn};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Callback, ErrorResponse, Request, Response},
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue, StatusCode},
+        Bytes, Message,
+    },
+    WebSocketStream,
+};
+use tracing::{debug, info, trace, warn};
 
-use crate::networking::{c2s::PacketReader, s2c::PacketWriter};
+use crate::{
+    config::NetworkConfig,
+    event::PlayerFloodEvent,
+    networking::{
+        c2s::PacketReader,
+        s2c::{DisconnectPlayerPacket, PacketWriter, PingPacket},
+        PacketString,
+    },
+};
 
-use super::{c2s::C2SPacket, s2c::S2CPacket, ClientPacketRegistry};
+use super::{
+    access::{AccessControl, BanList, ConnectionPermit},
+    c2s::C2SPacket,
+    intercept::InterceptorChain,
+    protocol::{self, PacketTables},
+    proxy::{self, ProxyProtocolConfig},
+    queue::{OutboundConfig, OutboundQueue},
+    ratelimit::{FloodAction, RateLimitConfig, RateLimiter, RateLimits},
+    s2c::S2CPacket,
+    Byte, ClientPacketRegistry,
+};
 
 pub struct ClientInfo {
-    pub packet_sender: mpsc::Sender<Box<dyn S2CPacket>>,
+    pub session: SessionId,
+    pub packet_sender: Arc<OutboundQueue>,
     pub addr: SocketAddr,
     pub player_id: Mutex<Option<EntityId>>,
+    pub activity: Arc<Activity>,
+    /// The latest version until the client identifies.
+    protocol_version: AtomicU8,
+}
+
+impl ClientInfo {
+    pub fn protocol_version(&self) -> Byte {
+        self.protocol_version.load(Ordering::Relaxed)
+    }
+}
+
+/// Identifies a single connection. Unlike addresses, these are never reused while the server runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SessionId(u64);
+
+impl Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Tracks when a client last sent a packet.
+#[derive(Debug)]
+pub struct Activity(Mutex<Instant>);
+
+impl Activity {
+    pub fn new() -> Self {
+        Self(Mutex::new(Instant::now()))
+    }
+
+    pub fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    /// How long ago the last packet was received.
+    pub fn idle(&self) -> Duration {
+        self.0.lock().unwrap().elapsed()
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub enum ClientMessage {
     Packet(ClientPacket),
-    Disconnect(SocketAddr),
+    Disconnect(SessionId),
+    Flood(PlayerFloodEvent),
 }
 
 pub struct ClientPacket {
@@ -38,103 +122,700 @@ impl ClientPacket {
     }
 }
 
-pub async fn listen<A: ToSocketAddrs>(
-    addr: A,
+/// Lets ClassiCube web clients connect over WebSockets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    pub enabled: bool,
+    /// A separate address to accept WebSocket connections on. When [`None`], WebSocket connections are accepted on the Classic port and told apart by their HTTP upgrade request.
+    pub addr: Option<String>,
+    /// The request path the web client is expected to connect to.
+    pub path: String,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            addr: None,
+            path: "/".into(),
+        }
+    }
+}
+
+/// The outbound queues of every open connection, whether or not it has joined yet.
+#[derive(Debug, Default)]
+pub struct Connections {
+    queues: Mutex<HashMap<SessionId, Arc<OutboundQueue>>>,
+    next_session: AtomicU64,
+    closed: Notify,
+}
+
+impl Connections {
+    fn allocate_session(&self) -> SessionId {
+        SessionId(self.next_session.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn insert(&self, session: SessionId, queue: Arc<OutboundQueue>) {
+        self.queues.lock().unwrap().insert(session, queue);
+    }
+
+    fn remove(&self, session: SessionId) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.remove(&session);
+
+        if queues.is_empty() {
+            self.closed.notify_waiters();
+        }
+    }
+
+    pub fn get(&self, session: SessionId) -> Option<Arc<OutboundQueue>> {
+        self.queues.lock().unwrap().get(&session).cloned()
+    }
+
+    /// Kicks the client of `session`. Returns whether it was still connected.
+    pub fn kick(&self, session: SessionId, reason: &str) -> bool {
+        match self.get(session) {
+            Some(queue) => {
+                queue.kick(reason);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sends every connection a [`super::s2c::DisconnectPlayerPacket`] after the packets already queued for it.
+    pub fn disconnect_all(&self, reason: &str) {
+        for queue in self.queues.lock().unwrap().values() {
+            queue.disconnect(reason);
+        }
+    }
+
+    /// Waits until every connection has been closed.
+    pub async fn wait_closed(&self) {
+        loop {
+            let closed = self.closed.notified();
+
+            if self.is_empty() {
+                return;
+            }
+
+            closed.await;
+        }
+    }
+}
+
+/// Settings for noticing dead and stalled connections.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepaliveConfig {
+    /// How often logged in clients are pinged, so that dead connections fail to be written to.
+    pub ping_interval_secs: u64,
+    /// How long a connection may take to send its PlayerIdentPacket, and to finish joining once given a player slot.
+    pub login_timeout_secs: u64,
+    /// How long a player may go without sending any packets before being kicked. 0 disables the timeout.
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval_secs: 5,
+            login_timeout_secs: 30,
+            idle_timeout_secs: 120,
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn login_timeout(&self) -> Duration {
+        Duration::from_secs(self.login_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+}
+
+/// The subprotocol requested by the ClassiCube web client.
+const WEBSOCKET_PROTOCOL: &str = "ClassiCube";
+const WEBSOCKET_BUFFER_SIZE: usize = 64 * 1024;
+/// How long packets still queued for a client are given to be written once it stops being read from.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the rest of a packet may take to arrive after its ID.
+const PACKET_TIMEOUT: Duration = Duration::from_secs(10);
+/// How much is buffered before being written out, even if more packets are ready.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+struct Listener {
+    tx: mpsc::Sender<ClientMessage>,
+    broadcaster: Arc<broadcast::Sender<Arc<Box<dyn S2CPacket>>>>,
+    packets: PacketTables,
+    interceptors: InterceptorChain,
+    websocket: Option<WebSocketConfig>,
+    outbound: OutboundConfig,
+    keepalive: KeepaliveConfig,
+    rate_limits: Option<Arc<RateLimits>>,
+    access: AccessControl,
+    proxy_protocol: ProxyProtocolConfig,
+    connections: Arc<Connections>,
+}
+
+/// Accepts connections until the returned future is dropped. Connections that were already accepted are kept open.
+pub async fn listen(
+    network: NetworkConfig,
     tx: mpsc::Sender<ClientMessage>,
     broadcaster: Arc<broadcast::Sender<Arc<Box<dyn S2CPacket>>>>,
     registry: ClientPacketRegistry,
+    interceptors: InterceptorChain,
+    connections: Arc<Connections>,
+    bans: BanList,
 ) {
-    let listener = TcpListener::bind(addr).await.unwrap();
-    let registry = Arc::new(registry);
+    let NetworkConfig {
+        bind,
+        websocket,
+        outbound,
+        keepalive,
+        rate_limit,
+        throttle,
+        ban_file: _,
+        proxy_protocol,
+    } = network;
+    let websocket = websocket.enabled.then_some(websocket);
+    let listener = TcpListener::bind(bind).await.unwrap();
+    let websocket_listener = match websocket.as_ref().and_then(|ws| ws.addr.as_ref()) {
+        Some(addr) => Some(TcpListener::bind(addr).await.unwrap()),
+        None => None,
+    };
+
+    let shared = Arc::new(Listener {
+        tx,
+        broadcaster,
+        packets: PacketTables::new(registry),
+        interceptors,
+        websocket,
+        outbound,
+        keepalive,
+        rate_limits: rate_limit_table(&rate_limit),
+        access: AccessControl::new(throttle, bans),
+        proxy_protocol,
+        connections,
+    });
 
     info!("Listening");
 
+    match websocket_listener {
+        Some(websocket_listener) => {
+            info!("Listening for WebSocket connections");
+            tokio::join!(
+                accept_loop(listener, shared.clone()),
+                accept_loop(websocket_listener, shared)
+            );
+        },
+        None => accept_loop(listener, shared).await,
+    }
+}
+
+fn rate_limit_table(config: &RateLimitConfig) -> Option<Arc<RateLimits>> {
+    if !config.enabled {
+        return None;
+    }
+
+    // Checked by ServerConfig::validate
+    Some(Arc::new(RateLimits::from_config(config).unwrap()))
+}
+
+async fn accept_loop(listener: TcpListener, shared: Arc<Listener>) {
     loop {
         let (socket, addr) = listener.accept().await.unwrap();
-        tokio::spawn(handle_client(
-            socket,
-            addr,
-            tx.clone(),
-            broadcaster.subscribe(),
-            registry.clone(),
-        ));
+
+        // The real address is only known once the header was read
+        if shared.proxy_protocol.trusts(addr.ip()) {
+            tokio::spawn(accept_proxied(socket, addr, shared.clone()));
+            continue;
+        }
+
+        match shared.access.admit(addr.ip()) {
+            Ok(permit) => {
+                tokio::spawn(accept(socket, addr, shared.clone(), permit));
+            },
+            Err(rejection) => {
+                debug!(%addr, ?rejection, "Rejected connection");
+
+                if let Some(reason) = rejection.reason() {
+                    tokio::spawn(reject(socket, reason.to_owned()));
+                }
+            },
+        }
     }
 }
 
-async fn handle_client(
+/// Reads the PROXY protocol header of a connection from a trusted proxy, then handles it as if it came from the address given there.
+async fn accept_proxied(
     mut socket: TcpStream,
+    proxy_addr: SocketAddr,
+    shared: Arc<Listener>,
+) -> Result<()> {
+    let header = tokio::time::timeout(
+        shared.keepalive.login_timeout(),
+        proxy::read_header(&mut socket),
+    )
+    .await;
+
+    let addr = match header {
+        Ok(Ok(Some(addr))) => addr,
+        Ok(Ok(None)) => {
+            trace!(%proxy_addr, "Closed proxy connection without a client address");
+            return Ok(());
+        },
+        Ok(Err(e)) => {
+            warn!(%proxy_addr, error = %e, "Invalid PROXY protocol header");
+            return Ok(());
+        },
+        Err(_) => {
+            debug!(%proxy_addr, "Proxy didn't send a PROXY protocol header in time");
+            return Ok(());
+        },
+    };
+
+    debug!(%addr, %proxy_addr, "Client connected through proxy");
+
+    match shared.access.admit(addr.ip()) {
+        Ok(permit) => accept(socket, addr, shared, permit).await,
+        Err(rejection) => {
+            debug!(%addr, ?rejection, "Rejected connection");
+
+            if let Some(reason) = rejection.reason() {
+                reject(socket, reason.to_owned()).await;
+            }
+
+            Ok(())
+        },
+    }
+}
+
+/// Sends a [`DisconnectPlayerPacket`] to a connection that was never handled. Errors are ignored as it is closed either way.
+async fn reject(mut socket: TcpStream, reason: String) {
+    let packet = DisconnectPlayerPacket {
+        disconnect_reason: PacketString::truncated(&reason),
+    };
+
+    let _ = tokio::time::timeout(
+        FLUSH_TIMEOUT,
+        write_packet(&packet, protocol::LATEST_VERSION, &mut socket),
+    )
+    .await;
+}
+
+/// Hands the connection to [`handle_client`], unwrapping WebSocket connections into a plain byte stream first.
+///
+/// The connection counts towards the limit of its IP for as long as `_permit` is held.
+async fn accept(
+    socket: TcpStream,
     addr: SocketAddr,
-    tx: mpsc::Sender<ClientMessage>,
-    mut broadcaster: broadcast::Receiver<Arc<Box<dyn S2CPacket>>>,
-    registry: Arc<ClientPacketRegistry>,
+    shared: Arc<Listener>,
+    _permit: ConnectionPermit,
 ) -> Result<()> {
-    info!("Incoming connection from: {addr}");
+    // Packets are batched before being written, so there is nothing to gain from Nagle's algorithm
+    socket.set_nodelay(true)?;
+
+    let Some(websocket) = shared.websocket.as_ref() else {
+        return handle_client(socket, addr, shared).await;
+    };
+
+    // Classic clients open with a PlayerIdentPacket (0x00) whereas WebSocket clients open with an HTTP GET
+    let mut first_byte = [0u8; 1];
+    let Ok(peeked) = tokio::time::timeout(
+        shared.keepalive.login_timeout(),
+        socket.peek(&mut first_byte),
+    )
+    .await
+    else {
+        debug!(%addr, "Client didn't send anything in time");
+        return Ok(());
+    };
+    if peeked? == 0 {
+        return Ok(());
+    }
+
+    if first_byte[0] != b'G' {
+        return handle_client(socket, addr, shared).await;
+    }
+
+    let Ok(stream) = tokio::time::timeout(
+        shared.keepalive.login_timeout(),
+        accept_hdr_async(
+            socket,
+            WebSocketHandshake {
+                path: &websocket.path,
+            },
+        ),
+    )
+    .await
+    else {
+        debug!(%addr, "Client didn't finish the WebSocket handshake in time");
+        return Ok(());
+    };
+    let stream = stream?;
+
+    debug!("Upgraded {addr} to a WebSocket connection");
+
+    let (client_side, bridge_side) = io::duplex(WEBSOCKET_BUFFER_SIZE);
+    tokio::spawn(bridge_websocket(stream, bridge_side));
+
+    handle_client(client_side, addr, shared).await
+}
+
+/// Rejects requests for other paths and agrees to the ClassiCube subprotocol when it is requested.
+struct WebSocketHandshake<'a> {
+    path: &'a str,
+}
+
+impl Callback for WebSocketHandshake<'_> {
+    fn on_request(
+        self,
+        request: &Request,
+        mut response: Response,
+    ) -> std::result::Result<Response, ErrorResponse> {
+        if request.uri().path() != self.path {
+            let mut error = ErrorResponse::new(None);
+            *error.status_mut() = StatusCode::NOT_FOUND;
+            return Err(error);
+        }
 
-    let (sender, mut receiver) = mpsc::channel(16);
+        let requests_protocol = request
+            .headers()
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == WEBSOCKET_PROTOCOL);
+
+        if requests_protocol {
+            response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(WEBSOCKET_PROTOCOL),
+            );
+        }
+
+        Ok(response)
+    }
+}
+
+/// Copies binary frames from the WebSocket into `stream` and wraps everything written to `stream` in binary frames.
+async fn bridge_websocket(
+    websocket: WebSocketStream<TcpStream>,
+    stream: DuplexStream,
+) -> Result<()> {
+    let (mut ws_sink, mut ws_stream) = websocket.split();
+    let (mut reader, mut writer) = io::split(stream);
+    let mut buf = vec![0u8; WEBSOCKET_BUFFER_SIZE];
+
+    loop {
+        tokio::select! {
+            message = ws_stream.next() => {
+                match message {
+                    Some(Ok(Message::Binary(data))) => writer.write_all(&data).await?,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {},
+                }
+            }
+            read = reader.read(&mut buf) => {
+                let read = read?;
+
+                if read == 0 {
+                    ws_sink.send(Message::Close(None)).await?;
+                    break;
+                }
+
+                ws_sink.send(Message::Binary(Bytes::copy_from_slice(&buf[..read]))).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    addr: SocketAddr,
+    shared: Arc<Listener>,
+) -> Result<()> {
+    let session = shared.connections.allocate_session();
+    info!(%session, "Incoming connection from: {addr}");
 
     let info = Arc::new(ClientInfo {
-        packet_sender: sender,
+        session,
+        packet_sender: Arc::new(OutboundQueue::new(addr, shared.outbound.clone())),
         addr,
         player_id: Mutex::new(None),
+        activity: Arc::new(Activity::new()),
+        protocol_version: AtomicU8::new(protocol::LATEST_VERSION),
     });
+    shared
+        .connections
+        .insert(session, info.packet_sender.clone());
+
+    let (mut reader, mut writer) = io::split(socket);
+    let writing = write_packets(&mut writer, &info, &shared);
+    tokio::pin!(writing);
+
+    let result = tokio::select! {
+        // The writer only stops early after a disconnect packet or a write error
+        result = &mut writing => result,
+        result = read_packets(&mut reader, &info, &shared) => {
+            info.packet_sender.close();
+
+            match tokio::time::timeout(FLUSH_TIMEOUT, writing).await {
+                Ok(flushed) => result.and(flushed),
+                Err(_) => {
+                    warn!(%addr, pending = info.packet_sender.len(), "Timed out flushing packets");
+                    result
+                },
+            }
+        },
+    };
+
+    info.packet_sender.close();
+    shared.connections.remove(session);
+
+    match &result {
+        Ok(()) => info!(%session, "Client disconnected"),
+        Err(e) => info!(%session, %addr, error = %e, "Client disconnected"),
+    }
+
+    shared.tx.send(ClientMessage::Disconnect(session)).await?;
+
+    result
+}
+
+async fn read_packets<R: AsyncRead + Unpin>(
+    socket: &mut R,
+    info: &Arc<ClientInfo>,
+    shared: &Listener,
+) -> Result<()> {
+    let addr = info.addr;
+    let queue = &info.packet_sender;
+    let mut broadcaster = shared.broadcaster.subscribe();
+
+    let mut rate_limiter = shared.rate_limits.clone().map(RateLimiter::new);
+    // Chosen by the protocol version of the PlayerIdentPacket
+    let mut registry: Option<&ClientPacketRegistry> = None;
+
+    let connected_at = Instant::now();
+    let ping_interval = shared.keepalive.ping_interval();
+    let mut keepalive =
+        tokio::time::interval_at((connected_at + ping_interval).into(), ping_interval);
 
     loop {
         tokio::select! {
-            packet = receiver.recv() => {
-                if let Some(packet) = packet {
-                    write_packet(packet.as_ref(), &mut socket).await?;
-                } else {
+            _ = keepalive.tick() => {
+                if info.player_id.lock().unwrap().is_some() {
+                    queue.send(Box::new(PingPacket));
+                } else if connected_at.elapsed() >= shared.keepalive.login_timeout() {
+                    debug!(%addr, "Client didn't log in in time");
+                    queue.kick("Took too long to log in");
                     break;
                 }
-            }
-            packet = broadcaster.recv() => {
-                if let Ok(packet) = packet {
-                    write_packet(packet.as_ref().as_ref(), &mut socket).await?;
-                } else {
+            },
+            packet = broadcaster.recv() => match packet {
+                Ok(packet) => queue.send_shared(packet),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(%addr, skipped, "Client fell behind on broadcast packets");
+                    queue.kick("Too slow to keep up");
                     break;
-                }
-            }
+                },
+                Err(RecvError::Closed) => break,
+            },
             packet_id = socket.read_u8() => {
-                if let Ok(packet_id) = packet_id {
-                    let client_packet = match registry.get(packet_id) {
-                        Some(packet_id) => packet_id,
-                        None => {
-                            warn!("Invalid packet ID: {packet_id}");
-                            continue;
-                        },
-                    };
-
-                    let mut packet_buf = vec![0u8; client_packet.size()];
-                    socket.read_exact(&mut packet_buf).await?;
-
-                    let packet = client_packet
-                        .deserialise(&mut PacketReader::new(packet_buf))
-                        .unwrap();
-
-
-                    // TODO: use env variable to make this if configurable
-                    // Ignore position packets
-                    if packet_id != 0x08 {
-                        trace!("Received packet: {packet:?}");
-                    }
+                let Ok(packet_id) = packet_id else {
+                    break;
+                };
+
+                let mut packet_buf = Vec::new();
+
+                let registry = match registry {
+                    Some(registry) => registry,
+                    None => {
+                        if packet_id != 0x00 {
+                            warn!(%addr, packet_id, "Received packet before PlayerIdent");
+                            queue.kick("Expected a PlayerIdent packet");
+                            break;
+                        }
+
+                        // The protocol version comes first, and decides how long the rest of the packet is
+                        let Ok(version) = tokio::time::timeout(PACKET_TIMEOUT, socket.read_u8()).await else {
+                            warn!(%addr, packet_id, "Timed out reading packet");
+                            queue.kick("Timed out");
+                            break;
+                        };
+                        let version = version?;
+
+                        let Some(table) = shared.packets.get(version) else {
+                            warn!(%addr, version, "Unsupported protocol version");
+                            queue.kick(&format!("Unsupported protocol version {version}"));
+                            break;
+                        };
 
-                    tx.send(ClientMessage::Packet(ClientPacket { packet, client_info: info.clone() })).await?;
-                } else {
+                        if version != protocol::LATEST_VERSION {
+                            debug!(%addr, version, "Client is using an older protocol version");
+                        }
+
+                        info.protocol_version.store(version, Ordering::Relaxed);
+                        packet_buf.push(version);
+                        registry.insert(table)
+                    },
+                };
+
+                // The size of the packet is unknown, so there is no way to resynchronise with the stream
+                let Some(client_packet) = registry.get(packet_id) else {
+                    warn!(%addr, packet_id, "Received unknown packet");
+                    queue.kick(&format!("Unknown packet ID {packet_id:#04x}"));
+                    break;
+                };
+
+                let read_from = packet_buf.len();
+                packet_buf.resize(client_packet.size(), 0);
+                // Stops clients from holding the connection open with partial packets
+                let Ok(read) = tokio::time::timeout(PACKET_TIMEOUT, socket.read_exact(&mut packet_buf[read_from..])).await else {
+                    warn!(%addr, packet_id, "Timed out reading packet");
+                    queue.kick("Timed out");
                     break;
+                };
+                read?;
+
+                if let Some(Err(flood)) = rate_limiter.as_mut().map(|limiter| limiter.check(packet_id)) {
+                    let kicked = flood.action == FloodAction::Kick;
+
+                    if flood.first || kicked {
+                        warn!(%addr, packet_id, kicked, "Client is flooding");
+
+                        let entity_id = *info.player_id.lock().unwrap();
+                        shared.tx.send(ClientMessage::Flood(PlayerFloodEvent { entity_id, session: info.session, addr, packet_id, kicked })).await?;
+                    }
+
+                    if kicked {
+                        queue.kick("Sending packets too fast");
+                        break;
+                    }
+
+                    continue;
                 }
+
+                let packet = match client_packet.deserialise(&mut PacketReader::new(packet_buf)) {
+                    Ok(packet) => {
+                        info.activity.touch();
+                        packet
+                    },
+                    Err(e) => {
+                        warn!(%addr, packet_id, error = %e, "Received malformed packet");
+                        queue.kick(&format!("Malformed packet {packet_id:#04x}"));
+                        break;
+                    },
+                };
+
+                // TODO: use env variable to make this if configurable
+                // Ignore position packets
+                if packet_id != 0x08 {
+                    trace!("Received packet: {packet:?}");
+                }
+
+                let Some(packet) = shared.interceptors.inbound(info, packet) else {
+                    continue;
+                };
+
+                shared.tx.send(ClientMessage::Packet(ClientPacket { packet, client_info: info.clone() })).await?;
             }
         }
     }
 
-    info!("Client disconnected");
-    tx.send(ClientMessage::Disconnect(addr)).await?;
+    Ok(())
+}
+
+/// Writes everything sent through the client's queue until it is closed or a packet closes the connection.
+///
+/// Ready packets are serialised into one buffer and written with a single call, after waiting up to the configured delay for more of them.
+async fn write_packets<W: AsyncWrite + Unpin>(
+    socket: &mut W,
+    info: &ClientInfo,
+    shared: &Listener,
+) -> Result<()> {
+    let queue = &info.packet_sender;
+    let max_delay = shared.outbound.max_flush_delay();
+    let mut batch = Vec::new();
+    let mut writer = PacketWriter::new_with_capacity(WRITE_BUFFER_SIZE);
+
+    while queue.recv_batch(&mut batch).await {
+        let deadline = tokio::time::Instant::now() + max_delay;
+        writer.set_protocol_version(info.protocol_version());
+
+        loop {
+            for packet in batch.drain(..) {
+                // Interceptors may change how a kick looks, but can't stop it
+                let closes = closes_connection(packet.as_ref());
+
+                if let Some(packet) = shared.interceptors.outbound(info, packet) {
+                    trace!("Sending packet: {:?}", packet);
+                    writer.write_packet(packet.as_ref())?;
+                }
+
+                if closes {
+                    socket.write_all(writer.as_bytes()).await?;
+                    socket.flush().await?;
+                    return Ok(());
+                }
+
+                if writer.len() >= WRITE_BUFFER_SIZE {
+                    socket.write_all(writer.as_bytes()).await?;
+                    writer.clear();
+                }
+            }
+
+            // The packets of a tick are sent moments apart, so they are worth waiting for
+            if max_delay.is_zero()
+                || !matches!(
+                    tokio::time::timeout_at(deadline, queue.recv_batch(&mut batch)).await,
+                    Ok(true)
+                )
+            {
+                break;
+            }
+        }
+
+        socket.write_all(writer.as_bytes()).await?;
+        socket.flush().await?;
+        writer.clear();
+    }
+
+    socket.flush().await?;
 
     Ok(())
 }
 
-async fn write_packet(packet: &dyn S2CPacket, socket: &mut TcpStream) -> Result<()> {
+/// The client is expected to drop the connection after a [`super::s2c::DisconnectPlayerPacket`], but it is closed from this side too in case it doesn't.
+fn closes_connection(packet: &dyn S2CPacket) -> bool {
+    packet.id() == 0x0e
+}
+
+async fn write_packet<S: AsyncWrite + Unpin>(
+    packet: &dyn S2CPacket,
+    protocol_version: Byte,
+    socket: &mut S,
+) -> Result<()> {
     trace!("Sending packet: {:?}", packet);
-    let mut writer = PacketWriter::new_with_capacity(1);
+    let mut writer = PacketWriter::new_with_capacity(1).with_protocol_version(protocol_version);
     writer.write_packet(packet)?;
     socket.write_all(&writer.into_inner()).await?;
 
@@ -1,12 +1,24 @@
 use anyhow::Result;
-use core::fmt::Debug;
+use core::fmt::{Debug, Display};
 use std::str::FromStr;
 
-use self::c2s::{C2SPacket, C2SPacketEntry, PacketReader};
+pub use vintage_macros::ClassicPacket;
 
+use self::{
+    c2s::{C2SPacket, C2SPacketEntry, PacketReader},
+    extension::Int,
+    s2c::PacketWriter,
+};
+
+pub mod access;
 pub mod c2s;
 pub mod extension;
+pub mod intercept;
 pub mod listener;
+pub mod protocol;
+pub mod proxy;
+pub mod queue;
+pub mod ratelimit;
 pub mod s2c;
 pub mod util;
 
@@ -52,6 +64,17 @@ impl PacketString {
         PacketString(data)
     }
 
+    /// Converts `data` without failing by replacing non-ASCII characters with `?` and cutting it off at [`Self::LENGTH`] characters.
+    pub fn truncated(data: &str) -> PacketString {
+        let mut buf = [b' '; Self::LENGTH];
+
+        for (b, c) in buf.iter_mut().zip(data.chars()) {
+            *b = if c.is_ascii() { c as u8 } else { b'?' };
+        }
+
+        PacketString(buf)
+    }
+
     pub const LENGTH: usize = 64;
 }
 
@@ -63,12 +86,9 @@ impl FromStr for PacketString {
     }
 }
 
-impl ToString for PacketString {
-    fn to_string(&self) -> String {
-        String::from_utf8(self.0.to_vec())
-            .unwrap()
-            .trim_end_matches(' ')
-            .to_string()
+impl Display for PacketString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(String::from_utf8_lossy(&self.0).trim_end_matches(' '))
     }
 }
 
@@ -80,6 +100,111 @@ impl Debug for PacketString {
     }
 }
 
+/// A type that can be a field of a packet deriving [`ClassicPacket`].
+pub trait PacketField: Sized {
+    /// How many bytes the field takes up on the wire.
+    const SIZE: usize;
+
+    fn read(reader: &mut PacketReader) -> Result<Self>;
+    fn write(&self, writer: &mut PacketWriter) -> Result<()>;
+}
+
+impl PacketField for Byte {
+    const SIZE: usize = 1;
+
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        reader.read_byte()
+    }
+
+    fn write(&self, writer: &mut PacketWriter) -> Result<()> {
+        writer.write_byte(*self)
+    }
+}
+
+impl PacketField for SByte {
+    const SIZE: usize = 1;
+
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        reader.read_sbyte()
+    }
+
+    fn write(&self, writer: &mut PacketWriter) -> Result<()> {
+        writer.write_sbyte(*self)
+    }
+}
+
+impl PacketField for FByte {
+    const SIZE: usize = 1;
+
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        reader.read_fbyte()
+    }
+
+    fn write(&self, writer: &mut PacketWriter) -> Result<()> {
+        writer.write_fbyte(self)
+    }
+}
+
+impl PacketField for Short {
+    const SIZE: usize = 2;
+
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        reader.read_short()
+    }
+
+    fn write(&self, writer: &mut PacketWriter) -> Result<()> {
+        writer.write_short(*self)
+    }
+}
+
+impl PacketField for FShort {
+    const SIZE: usize = 2;
+
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        reader.read_fshort()
+    }
+
+    fn write(&self, writer: &mut PacketWriter) -> Result<()> {
+        writer.write_fshort(self)
+    }
+}
+
+impl PacketField for Int {
+    const SIZE: usize = 4;
+
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        reader.read_int()
+    }
+
+    fn write(&self, writer: &mut PacketWriter) -> Result<()> {
+        writer.write_int(*self)
+    }
+}
+
+impl PacketField for PacketString {
+    const SIZE: usize = PacketString::LENGTH;
+
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        reader.read_packet_string()
+    }
+
+    fn write(&self, writer: &mut PacketWriter) -> Result<()> {
+        writer.write_packet_string(self)
+    }
+}
+
+impl PacketField for ByteArray {
+    const SIZE: usize = 1024;
+
+    fn read(reader: &mut PacketReader) -> Result<Self> {
+        reader.read_byte_array()
+    }
+
+    fn write(&self, writer: &mut PacketWriter) -> Result<()> {
+        writer.write_byte_array(self)
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct ClientPacketRegistry {
     packets: Vec<Option<ClientPacketRegistryEntry>>,
@@ -106,7 +231,7 @@ impl ClientPacketRegistry {
     }
 
     pub fn get(&self, id: Byte) -> Option<&ClientPacketRegistryEntry> {
-        self.packets[id as usize].as_ref()
+        self.packets.get(id as usize).and_then(Option::as_ref)
     }
 }
 
@@ -0,0 +1,213 @@
+use crate::world::Block;
+
+use super::{c2s, Byte, ClientPacketRegistry};
+
+/// The protocol version of Classic 0.28 to 0.30, the only one with CPE.
+pub const LATEST_VERSION: Byte = 7;
+/// The oldest protocol version that can connect.
+pub const OLDEST_VERSION: Byte = 3;
+
+/// The client packets of every supported protocol version, chosen once a client's PlayerIdentPacket says which one it speaks.
+#[derive(Debug)]
+pub struct PacketTables {
+    latest: ClientPacketRegistry,
+    /// Versions 3 to 6 only differ from each other in which blocks they know.
+    legacy: ClientPacketRegistry,
+}
+
+impl PacketTables {
+    /// `latest` is used for the current protocol, including any packets registered by extensions and plugins.
+    pub fn new(latest: ClientPacketRegistry) -> Self {
+        let mut legacy = ClientPacketRegistry::default();
+        legacy.register::<c2s::LegacyPlayerIdentPacket>();
+        legacy.register::<c2s::SetBlockPacket>();
+        legacy.register::<c2s::PositionPacket>();
+        legacy.register::<c2s::MessagePacket>();
+
+        Self { latest, legacy }
+    }
+
+    pub fn get(&self, version: Byte) -> Option<&ClientPacketRegistry> {
+        match version {
+            LATEST_VERSION => Some(&self.latest),
+            OLDEST_VERSION..LATEST_VERSION => Some(&self.legacy),
+            _ => None,
+        }
+    }
+}
+
+/// The block with the highest ID that clients of `version` know of.
+fn last_block(version: Byte) -> Block {
+    match version {
+        LATEST_VERSION.. => Block::Obsidian,
+        6 => Block::BlockOfGold,
+        5 => Block::Glass,
+        _ => Block::Leaves,
+    }
+}
+
+/// Replaces blocks added after `version` with a similar looking one the client knows of.
+pub fn block_for_version(block: Block, version: Byte) -> Block {
+    let last = last_block(version) as u8;
+    let mut block = block;
+
+    while block as u8 > last {
+        block = fallback(block);
+    }
+
+    block
+}
+
+/// A block that was added earlier than `block` and looks like it. Every fallback has a lower ID than the block it replaces.
+fn fallback(block: Block) -> Block {
+    use Block::*;
+
+    match block {
+        Sponge => Sand,
+        Glass => Leaves,
+        RedCloth | OrangeCloth | YellowCloth | ChartreuseCloth | GreenCloth | SpringGreenCloth
+        | CyanCloth | CapriCloth | UltramarineCloth | PurpleCloth | VioletCloth | MagentaCloth
+        | RoseCloth | DarkGreyCloth | LightGreyCloth | WhiteCloth => Sponge,
+        Flower | Rose | BrownMushroom | RedMushroom => Sapling,
+        BlockOfGold => GoldOre,
+        BlockOfIron => IronOre,
+        DoubleSlab | Slab | Obsidian => Stone,
+        Bricks | TNT => RedCloth,
+        Bookshelf => Planks,
+        MossyCobbleStone => Cobblestone,
+        _ => Air,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use enum_primitive::FromPrimitive;
+
+    use super::*;
+    use crate::networking::{
+        s2c::{self, PacketWriter, S2CPacket},
+        PacketString,
+    };
+
+    fn written(packet: &dyn S2CPacket, version: Byte) -> Vec<u8> {
+        let mut writer = PacketWriter::new_empty().with_protocol_version(version);
+        writer.write_packet(packet).unwrap();
+        writer.into_inner()
+    }
+
+    #[test]
+    fn packet_tables() {
+        let mut latest = ClientPacketRegistry::default();
+        crate::default::add_default_packets(&mut latest);
+        let tables = PacketTables::new(latest);
+
+        // The size of the PlayerIdentPacket, which has no padding byte before version 7
+        for (version, ident_size) in [(3, 129), (4, 129), (5, 129), (6, 129), (7, 130)] {
+            let registry = tables.get(version).unwrap();
+            assert_eq!(registry.get(0x00).unwrap().size(), ident_size, "v{version}");
+            assert!(registry.get(0x05).is_some());
+        }
+
+        assert!(tables.get(2).is_none());
+        assert!(tables.get(8).is_none());
+    }
+
+    #[test]
+    fn blocks_for_each_version() {
+        use Block::*;
+
+        // The block sent to clients of versions 3 to 7
+        let table = [
+            (Stone, [Stone, Stone, Stone, Stone, Stone]),
+            (Leaves, [Leaves, Leaves, Leaves, Leaves, Leaves]),
+            (Sponge, [Sand, Sand, Sponge, Sponge, Sponge]),
+            (Glass, [Leaves, Leaves, Glass, Glass, Glass]),
+            (RedCloth, [Sand, Sand, Sponge, RedCloth, RedCloth]),
+            (Flower, [Sapling, Sapling, Sapling, Flower, Flower]),
+            (
+                BlockOfGold,
+                [GoldOre, GoldOre, GoldOre, BlockOfGold, BlockOfGold],
+            ),
+            (
+                BlockOfIron,
+                [IronOre, IronOre, IronOre, IronOre, BlockOfIron],
+            ),
+            (Bricks, [Sand, Sand, Sponge, RedCloth, Bricks]),
+            (
+                MossyCobbleStone,
+                [
+                    Cobblestone,
+                    Cobblestone,
+                    Cobblestone,
+                    Cobblestone,
+                    MossyCobbleStone,
+                ],
+            ),
+            (Obsidian, [Stone, Stone, Stone, Stone, Obsidian]),
+        ];
+
+        for (block, expected) in table {
+            for (version, expected) in (OLDEST_VERSION..=LATEST_VERSION).zip(expected) {
+                assert_eq!(
+                    block_for_version(block, version),
+                    expected,
+                    "{block:?} in v{version}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_block_is_known_after_fallback() {
+        for version in OLDEST_VERSION..=LATEST_VERSION {
+            for id in 0..=Block::Obsidian as u8 {
+                let block = Block::from_u8(id).unwrap();
+                assert!(block_for_version(block, version) as u8 <= last_block(version) as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn set_block_uses_fallbacks() {
+        let packet = s2c::SetBlockPacket {
+            x: 1,
+            y: 2,
+            z: 3,
+            block_type: Block::Obsidian as Byte,
+        };
+
+        for (version, block) in [(3, Block::Stone), (6, Block::Stone), (7, Block::Obsidian)] {
+            assert_eq!(*written(&packet, version).last().unwrap(), block as Byte);
+        }
+    }
+
+    #[test]
+    fn server_ident_user_type() {
+        let packet = s2c::ServerIdentPacket {
+            protocol_version: 7,
+            server_name: PacketString::truncated("name"),
+            server_motd: PacketString::truncated("motd"),
+            user_type: 0x64,
+        };
+
+        for version in OLDEST_VERSION..=LATEST_VERSION {
+            let data = written(&packet, version);
+            if version < 7 {
+                assert_eq!(data.len(), 130, "v{version}");
+            } else {
+                assert_eq!(data.len(), 131);
+                assert_eq!(data[130], 0x64);
+            }
+        }
+    }
+
+    #[test]
+    fn update_user_type_needs_version_7() {
+        let packet = s2c::UpdateUserTypePacket { user_type: 0x64 };
+
+        for version in OLDEST_VERSION..LATEST_VERSION {
+            assert!(written(&packet, version).is_empty(), "v{version}");
+        }
+        assert_eq!(written(&packet, LATEST_VERSION), [0x0f, 0x64]);
+    }
+}
@@ -0,0 +1,234 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::access::IpRange;
+
+/// Reading the real client address from a PROXY protocol header, as sent by HAProxy and similar load balancers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocolConfig {
+    pub enabled: bool,
+    /// Connections from these addresses must start with a PROXY protocol header. Connections from anywhere else are taken as direct.
+    pub trusted_proxies: Vec<IpRange>,
+}
+
+impl ProxyProtocolConfig {
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.enabled && self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+}
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest possible v1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads a v1 or v2 header, leaving the stream at the first byte after it.
+///
+/// Returns [`None`] if the proxy didn't pass on a client address, as it does for its own health checks.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    match stream.read_u8().await? {
+        b'P' => read_v1(stream).await,
+        b'\r' => read_v2(stream).await,
+        byte => bail!("Expected a PROXY protocol header, got {byte:#04x}"),
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    let mut line = vec![b'P'];
+
+    // Read a byte at a time so that nothing after the header is consumed
+    while !line.ends_with(b"\r\n") {
+        ensure!(line.len() < V1_MAX_LENGTH, "PROXY v1 header is too long");
+        line.push(stream.read_u8().await?);
+    }
+
+    ensure!(line.starts_with(V1_PREFIX), "Malformed PROXY v1 header");
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])?;
+    let mut parts = line.split(' ');
+
+    match parts.next() {
+        Some("TCP4" | "TCP6") => {},
+        Some("UNKNOWN") => return Ok(None),
+        _ => bail!("Unsupported PROXY v1 protocol in {line:?}"),
+    }
+
+    let (Some(source), Some(_destination), Some(port), Some(_destination_port), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        bail!("Malformed PROXY v1 header {line:?}");
+    };
+
+    let ip = source
+        .parse::<IpAddr>()
+        .with_context(|| format!("Invalid source address in PROXY v1 header {line:?}"))?;
+    let port = port
+        .parse::<u16>()
+        .with_context(|| format!("Invalid source port in PROXY v1 header {line:?}"))?;
+
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<SocketAddr>> {
+    let mut header = [0u8; 16];
+    header[0] = b'\r';
+    stream.read_exact(&mut header[1..]).await?;
+
+    ensure!(
+        &header[..12] == V2_SIGNATURE,
+        "Malformed PROXY v2 signature"
+    );
+    ensure!(header[12] >> 4 == 2, "Unsupported PROXY protocol version");
+
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await?;
+
+    match header[12] & 0x0f {
+        // LOCAL, sent for the proxy's own connections
+        0x0 => return Ok(None),
+        0x1 => {},
+        command => bail!("Unsupported PROXY v2 command {command:#x}"),
+    }
+
+    // The high nibble is the address family, the low one the transport protocol
+    let addr = match header[13] {
+        0x11 => {
+            ensure!(payload.len() >= 12, "PROXY v2 header is too short");
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            SocketAddr::new(ip.into(), port)
+        },
+        0x21 => {
+            ensure!(payload.len() >= 36, "PROXY v2 header is too short");
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[..16])?);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            SocketAddr::new(ip.into(), port)
+        },
+        _ => return Ok(None),
+    };
+
+    Ok(Some(addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut data: &[u8]) -> Result<Option<SocketAddr>> {
+        let addr = read_header(&mut data).await;
+        // Nothing past the header may be consumed
+        if addr.is_ok() {
+            assert_eq!(data, b"rest");
+        }
+        addr
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(payload);
+        header.extend_from_slice(b"rest");
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let addr = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25565\r\nrest").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let addr = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4242 25565\r\nrest").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:4242".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let addr = read(b"PROXY UNKNOWN\r\nrest").await;
+        assert_eq!(addr.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_without_crlf() {
+        let mut header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25565".to_vec();
+        header.resize(200, b' ');
+
+        let err = read(&header).await.unwrap_err();
+        assert_eq!(err.to_string(), "PROXY v1 header is too long");
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        assert!(read(b"PROXY TCP4 192.0.2.1 56324\r\nrest").await.is_err());
+        assert!(read(b"PROXY TCP4 nowhere 198.51.100.1 1 2\r\nrest")
+            .await
+            .is_err());
+        assert!(read(b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\nrest")
+            .await
+            .is_err());
+        assert!(read(b"PRAXY TCP4 192.0.2.1 198.51.100.1 1 2\r\nrest")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_ipv4() {
+        let payload = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x63, 0xdd];
+        let addr = read(&v2(0x1, 0x11, &payload)).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_ipv6() {
+        let mut payload = [0u8; 36];
+        payload[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        payload[16..32].copy_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        payload[32..34].copy_from_slice(&4242u16.to_be_bytes());
+
+        let addr = read(&v2(0x1, 0x21, &payload)).await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:4242".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let addr = read(&v2(0x0, 0x00, &[])).await;
+        assert_eq!(addr.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_truncated() {
+        // Claims a longer payload than is sent
+        let mut header = v2(0x1, 0x11, &[192, 0, 2, 1]);
+        header[15] = 12;
+        header.truncate(16 + 4);
+        assert!(read(&header).await.is_err());
+
+        // Too short for the address family
+        assert!(read(&v2(0x1, 0x11, &[192, 0, 2, 1])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_bad_signature() {
+        let mut header = v2(0x1, 0x11, &[0; 12]);
+        header[5] = b'X';
+
+        let err = read(&header).await.unwrap_err();
+        assert_eq!(err.to_string(), "Malformed PROXY v2 signature");
+    }
+
+    #[tokio::test]
+    async fn not_a_header() {
+        assert!(read(&[0x00, 0x07]).await.is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::world::PlayerId;
+
+use super::{
+    s2c::{DisconnectPlayerPacket, S2CPacket},
+    PacketString,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryPolicy {
+    /// Never dropped. A client that stays too far behind on these is kicked.
+    MustDeliver,
+    /// Once too many of these are queued, the oldest one is dropped.
+    DropOldest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundConfig {
+    /// The number of queued [`DeliveryPolicy::MustDeliver`] packets a client may fall behind by.
+    pub max_pending: usize,
+    /// The number of queued [`DeliveryPolicy::DropOldest`] packets kept before the oldest are dropped.
+    pub max_droppable: usize,
+    /// How long a client may stay over `max_pending` before it is kicked.
+    pub overload_grace_secs: u64,
+    /// How long ready packets may wait for more to be written together with them. 0 writes whatever is ready straight away.
+    pub max_flush_delay_ms: u64,
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            max_pending: 4096,
+            max_droppable: 256,
+            overload_grace_secs: 10,
+            max_flush_delay_ms: 2,
+        }
+    }
+}
+
+impl OutboundConfig {
+    pub fn max_flush_delay(&self) -> Duration {
+        Duration::from_millis(self.max_flush_delay_ms)
+    }
+}
+
+/// Packets waiting to be written to a client's socket.
+///
+/// Sending never blocks, so it is safe to use from the world thread. Packets sent after the queue was closed are discarded.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    addr: SocketAddr,
+    config: OutboundConfig,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    packets: VecDeque<QueuedPacket>,
+    droppable: usize,
+    over_budget_since: Option<Instant>,
+    /// Players whose movement packets were dropped, so the client's idea of their position is wrong.
+    desynced: HashSet<PlayerId>,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct QueuedPacket {
+    packet: Arc<dyn S2CPacket>,
+    policy: DeliveryPolicy,
+    player_id: Option<PlayerId>,
+}
+
+impl OutboundQueue {
+    pub fn new(addr: SocketAddr, config: OutboundConfig) -> Self {
+        Self {
+            addr,
+            config,
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn send(&self, packet: Box<dyn S2CPacket>) {
+        self.push(Arc::from(packet), DeliveryPolicy::MustDeliver, None);
+    }
+
+    pub fn send_shared(&self, packet: Arc<dyn S2CPacket>) {
+        self.push(packet, DeliveryPolicy::MustDeliver, None);
+    }
+
+    /// Sends a packet describing the movement of `player_id`, which may be dropped if the client falls behind.
+    ///
+    /// See [`Self::take_desync`] for recovering from dropped packets.
+    pub fn send_movement(&self, player_id: PlayerId, packet: Box<dyn S2CPacket>) {
+        self.push(
+            Arc::from(packet),
+            DeliveryPolicy::DropOldest,
+            Some(player_id),
+        );
+    }
+
+    /// Returns whether a movement packet of `player_id` was dropped since the last call, in which case the next update should be absolute.
+    pub fn take_desync(&self, player_id: PlayerId) -> bool {
+        self.state.lock().unwrap().desynced.remove(&player_id)
+    }
+
+    fn push(
+        &self,
+        packet: Arc<dyn S2CPacket>,
+        policy: DeliveryPolicy,
+        player_id: Option<PlayerId>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return;
+        }
+
+        state.packets.push_back(QueuedPacket {
+            packet,
+            policy,
+            player_id,
+        });
+
+        match policy {
+            DeliveryPolicy::DropOldest => {
+                state.droppable += 1;
+
+                if state.droppable > self.config.max_droppable {
+                    state.drop_oldest();
+                }
+            },
+            DeliveryPolicy::MustDeliver => {
+                if state.packets.len() - state.droppable > self.config.max_pending {
+                    let since = *state.over_budget_since.get_or_insert_with(Instant::now);
+
+                    if since.elapsed() > Duration::from_secs(self.config.overload_grace_secs) {
+                        warn!(addr = %self.addr, pending = state.packets.len(), "Client stayed over its outbound budget");
+                        state.kick("Too slow to keep up");
+                    }
+                }
+            },
+        }
+
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Discards everything still queued and sends a [`DisconnectPlayerPacket`] with `reason`. No packets are accepted afterwards.
+    pub fn kick(&self, reason: &str) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return;
+        }
+
+        state.kick(reason);
+
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Like [`Self::kick`], but whatever is already queued is sent before the [`DisconnectPlayerPacket`].
+    pub fn disconnect(&self, reason: &str) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return;
+        }
+
+        state.close_with(reason);
+
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Stops accepting packets. Whatever is already queued is still written.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits for packets and moves all of them into `batch`. Returns `false` once the queue is closed and empty.
+    pub async fn recv_batch(&self, batch: &mut Vec<Arc<dyn S2CPacket>>) -> bool {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+
+                if !state.packets.is_empty() {
+                    batch.extend(state.packets.drain(..).map(|queued| queued.packet));
+                    state.droppable = 0;
+                    state.over_budget_since = None;
+
+                    return true;
+                }
+
+                if state.closed {
+                    return false;
+                }
+            }
+
+            self.notify.notified().await;
+        }
+    }
+}
+
+impl QueueState {
+    fn drop_oldest(&mut self) {
+        let Some(index) = self
+            .packets
+            .iter()
+            .position(|queued| queued.policy == DeliveryPolicy::DropOldest)
+        else {
+            return;
+        };
+
+        if let Some(dropped) = self.packets.remove(index) {
+            self.droppable -= 1;
+
+            if let Some(player_id) = dropped.player_id {
+                self.desynced.insert(player_id);
+            }
+        }
+    }
+
+    fn kick(&mut self, reason: &str) {
+        self.packets.clear();
+        self.droppable = 0;
+        self.close_with(reason);
+    }
+
+    fn close_with(&mut self, reason: &str) {
+        self.packets.push_back(QueuedPacket {
+            packet: Arc::new(DisconnectPlayerPacket {
+                disconnect_reason: PacketString::truncated(reason),
+            }),
+            policy: DeliveryPolicy::MustDeliver,
+            player_id: None,
+        });
+        self.closed = true;
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::Byte;
+
+/// What happens to packets sent faster than their [`PacketLimit`] allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FloodAction {
+    /// Ignores the excess packets.
+    Drop,
+    /// Disconnects the client.
+    Kick,
+}
+
+/// A token bucket refilled at `rate` packets per second, holding at most `burst`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PacketLimit {
+    pub rate: f32,
+    pub burst: u32,
+    pub action: FloodAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Applies to every packet type without its own limit.
+    pub default: PacketLimit,
+    /// Limits keyed by packet ID in hex, such as `"0x05"`.
+    pub packets: BTreeMap<String, PacketLimit>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            default: PacketLimit {
+                rate: 40.,
+                burst: 80,
+                action: FloodAction::Kick,
+            },
+            packets: BTreeMap::from([
+                // SetBlockPacket
+                (
+                    "0x05".into(),
+                    PacketLimit {
+                        rate: 12.,
+                        burst: 40,
+                        action: FloodAction::Kick,
+                    },
+                ),
+                // MessagePacket
+                (
+                    "0x0d".into(),
+                    PacketLimit {
+                        rate: 2.,
+                        burst: 6,
+                        action: FloodAction::Drop,
+                    },
+                ),
+            ]),
+        }
+    }
+}
+
+/// The limits of a [`RateLimitConfig`], looked up by packet ID.
+#[derive(Debug)]
+pub struct RateLimits {
+    default: PacketLimit,
+    packets: HashMap<Byte, PacketLimit>,
+}
+
+impl RateLimits {
+    pub fn from_config(config: &RateLimitConfig) -> Result<Self> {
+        let packets = config
+            .packets
+            .iter()
+            .map(|(id, limit)| Ok((parse_packet_id(id)?, *limit)))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            default: config.default,
+            packets,
+        })
+    }
+
+    pub fn get(&self, packet_id: Byte) -> &PacketLimit {
+        self.packets.get(&packet_id).unwrap_or(&self.default)
+    }
+}
+
+fn parse_packet_id(id: &str) -> Result<Byte> {
+    let hex = id
+        .strip_prefix("0x")
+        .with_context(|| format!("Packet ID {id} must be written in hex, like 0x05"))?;
+
+    Byte::from_str_radix(hex, 16).with_context(|| format!("Invalid packet ID {id}"))
+}
+
+/// Packets over the limit.
+#[derive(Debug, Clone, Copy)]
+pub struct Flood {
+    pub action: FloodAction,
+    /// Whether this is the first packet over the limit since the last one that was let through.
+    pub first: bool,
+}
+
+/// Token buckets for each packet type of a single connection.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: Arc<RateLimits>,
+    buckets: HashMap<Byte, TokenBucket>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f32,
+    last_refill: Instant,
+    flooding: bool,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Arc<RateLimits>) -> Self {
+        Self {
+            limits,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token for a packet, failing if the bucket of its type is empty.
+    pub fn check(&mut self, packet_id: Byte) -> Result<(), Flood> {
+        self.check_at(packet_id, Instant::now())
+    }
+
+    fn check_at(&mut self, packet_id: Byte, now: Instant) -> Result<(), Flood> {
+        let limit = self.limits.get(packet_id);
+        let bucket = self.buckets.entry(packet_id).or_insert(TokenBucket {
+            tokens: limit.burst as f32,
+            last_refill: now,
+            flooding: false,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f32();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(limit.burst as f32);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            bucket.flooding = false;
+            return Ok(());
+        }
+
+        let first = !bucket.flooding;
+        bucket.flooding = true;
+
+        Err(Flood {
+            action: limit.action,
+            first,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limiter() -> RateLimiter {
+        let config = RateLimitConfig {
+            enabled: true,
+            default: PacketLimit {
+                rate: 2.,
+                burst: 3,
+                action: FloodAction::Kick,
+            },
+            packets: BTreeMap::from([(
+                "0x0d".into(),
+                PacketLimit {
+                    rate: 1.,
+                    burst: 1,
+                    action: FloodAction::Drop,
+                },
+            )]),
+        };
+
+        RateLimiter::new(Arc::new(RateLimits::from_config(&config).unwrap()))
+    }
+
+    #[test]
+    fn burst_runs_out() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(0x08, now).is_ok());
+        }
+
+        let flood = limiter.check_at(0x08, now).unwrap_err();
+        assert_eq!(flood.action, FloodAction::Kick);
+        assert!(flood.first);
+        assert!(!limiter.check_at(0x08, now).unwrap_err().first);
+    }
+
+    #[test]
+    fn tokens_refill() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            limiter.check_at(0x08, now).unwrap();
+        }
+        assert!(limiter.check_at(0x08, now).is_err());
+
+        // 2 packets a second
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at(0x08, later).is_ok());
+        assert!(limiter.check_at(0x08, later).is_err());
+
+        // Never more than the burst, however long the client was quiet
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check_at(0x08, much_later).is_ok());
+        }
+        let flood = limiter.check_at(0x08, much_later).unwrap_err();
+        assert!(flood.first, "a packet was let through since the last flood");
+    }
+
+    #[test]
+    fn packets_have_their_own_limits() {
+        let mut limiter = limiter();
+        let now = Instant::now();
+
+        assert!(limiter.check_at(0x0d, now).is_ok());
+        let flood = limiter.check_at(0x0d, now).unwrap_err();
+        assert_eq!(flood.action, FloodAction::Drop);
+
+        // Other packet types have separate buckets
+        assert!(limiter.check_at(0x05, now).is_ok());
+        assert!(limiter.check_at(0x08, now).is_ok());
+    }
+
+    #[test]
+    fn packet_ids_must_be_hex() {
+        assert_eq!(parse_packet_id("0x0d").unwrap(), 0x0d);
+        assert_eq!(parse_packet_id("0xff").unwrap(), 0xff);
+        assert!(parse_packet_id("13").is_err());
+        assert!(parse_packet_id("0x100").is_err());
+        assert!(parse_packet_id("0xzz").is_err());
+
+        let config = RateLimitConfig {
+            packets: BTreeMap::from([("5".into(), RateLimitConfig::default().default)]),
+            ..Default::default()
+        };
+        assert!(RateLimits::from_config(&config).is_err());
+    }
+}
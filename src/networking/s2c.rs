@@ -1,6 +1,7 @@
 pub mod util;
 
 use std::{
+    any::Any,
     fmt::Debug,
     io::{Cursor, Write},
     str::FromStr,
@@ -8,33 +9,75 @@ use std::{
 
 use anyhow::Result;
 use byteorder::{BigEndian, WriteBytesExt};
+use enum_primitive::FromPrimitive;
 
-use super::{extension::Int, Byte, ByteArray, FByte, FShort, PacketString, SByte, Short};
+use crate::world::Block;
+
+use super::{
+    extension::Int, protocol, Byte, ByteArray, ClassicPacket, FByte, FShort, PacketString, SByte,
+    Short,
+};
 
 pub struct PacketWriter {
     buffer: Cursor<Vec<u8>>,
+    protocol_version: Byte,
 }
 
 impl PacketWriter {
     pub fn new(data: Vec<u8>) -> PacketWriter {
         PacketWriter {
             buffer: Cursor::new(data),
+            protocol_version: protocol::LATEST_VERSION,
         }
     }
 
     pub fn new_empty() -> PacketWriter {
-        PacketWriter {
-            buffer: Cursor::new(Vec::new()),
-        }
+        Self::new(Vec::new())
     }
 
     pub fn new_with_capacity(capacity: usize) -> PacketWriter {
-        PacketWriter {
-            buffer: Cursor::new(Vec::with_capacity(capacity)),
-        }
+        Self::new(Vec::with_capacity(capacity))
+    }
+
+    /// Writes packets the way clients of `protocol_version` expect them.
+    pub fn with_protocol_version(mut self, protocol_version: Byte) -> PacketWriter {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    pub fn protocol_version(&self) -> Byte {
+        self.protocol_version
+    }
+
+    pub fn set_protocol_version(&mut self, protocol_version: Byte) {
+        self.protocol_version = protocol_version;
+    }
+
+    /// Everything written so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buffer.get_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.get_ref().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
+    /// Empties the buffer, keeping its capacity so it can be reused.
+    pub fn clear(&mut self) {
+        self.buffer.get_mut().clear();
+        self.buffer.set_position(0);
+    }
+
+    /// Writes nothing if the packet doesn't exist in the protocol version being written.
     pub fn write_packet(&mut self, packet: &dyn S2CPacket) -> Result<()> {
+        if self.protocol_version < packet.min_protocol_version() {
+            return Ok(());
+        }
+
         self.write_byte(packet.id())?;
         packet.serialise(self)?;
         Ok(())
@@ -81,9 +124,40 @@ impl PacketWriter {
     }
 }
 
-pub trait S2CPacket: Send + Sync + Debug {
+pub trait S2CPacket: Send + Sync + Debug + Any {
     fn serialise(&self, writer: &mut PacketWriter) -> Result<()>;
     fn id(&self) -> Byte;
+
+    /// The oldest protocol version that has this packet.
+    fn min_protocol_version(&self) -> Byte {
+        protocol::OLDEST_VERSION
+    }
+}
+
+impl dyn S2CPacket {
+    /// Returns the packet as a `P` if that is what it is, looking through a [`Box`] around it.
+    pub fn downcast_ref<P: S2CPacket>(&self) -> Option<&P> {
+        let any = self as &dyn Any;
+
+        any.downcast_ref().or_else(|| {
+            any.downcast_ref::<Box<dyn S2CPacket>>()
+                .and_then(|inner| inner.downcast_ref())
+        })
+    }
+}
+
+impl<P: S2CPacket + ?Sized> S2CPacket for Box<P> {
+    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
+        (**self).serialise(writer)
+    }
+
+    fn id(&self) -> Byte {
+        (**self).id()
+    }
+
+    fn min_protocol_version(&self) -> Byte {
+        (**self).min_protocol_version()
+    }
 }
 
 #[derive(Debug)]
@@ -91,6 +165,7 @@ pub struct ServerIdentPacket {
     pub protocol_version: Byte,
     pub server_name: PacketString,
     pub server_motd: PacketString,
+    /// Left out for clients older than protocol version 7.
     pub user_type: Byte,
 }
 
@@ -99,92 +174,63 @@ impl S2CPacket for ServerIdentPacket {
         writer.write_byte(self.protocol_version)?;
         writer.write_packet_string(&self.server_name)?;
         writer.write_packet_string(&self.server_motd)?;
-        writer.write_byte(self.user_type)
-    }
 
-    fn id(&self) -> Byte {
-        0x00
-    }
-}
-
-#[derive(Debug)]
-pub struct PingPacket;
+        if writer.protocol_version() >= protocol::LATEST_VERSION {
+            writer.write_byte(self.user_type)?;
+        }
 
-impl S2CPacket for PingPacket {
-    fn serialise(&self, _writer: &mut PacketWriter) -> Result<()> {
         Ok(())
     }
 
     fn id(&self) -> Byte {
-        0x01
+        0x00
     }
 }
 
-#[derive(Debug)]
-pub struct LevelInitPacket;
-
-impl S2CPacket for LevelInitPacket {
-    fn serialise(&self, _writer: &mut PacketWriter) -> Result<()> {
-        Ok(())
-    }
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x01, s2c)]
+pub struct PingPacket;
 
-    fn id(&self) -> Byte {
-        0x02
-    }
-}
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x02, s2c)]
+pub struct LevelInitPacket;
 
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x03, s2c)]
 pub struct LevelDataChunkPacket {
     pub chunk_length: Short,
     pub chunk_data: ByteArray,
     pub percent_complete: Byte,
 }
 
-impl S2CPacket for LevelDataChunkPacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_short(self.chunk_length)?;
-        writer.write_byte_array(&self.chunk_data)?;
-        writer.write_byte(self.percent_complete)
-    }
-
-    fn id(&self) -> Byte {
-        0x03
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x04, s2c)]
 pub struct LevelFinalisePacket {
     pub x_size: Short,
     pub y_size: Short,
     pub z_size: Short,
 }
 
-impl S2CPacket for LevelFinalisePacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_short(self.x_size)?;
-        writer.write_short(self.y_size)?;
-        writer.write_short(self.z_size)
-    }
-
-    fn id(&self) -> Byte {
-        0x04
-    }
-}
-
 #[derive(Debug)]
 pub struct SetBlockPacket {
     pub x: Short,
     pub y: Short,
     pub z: Short,
+    /// Replaced with a fallback for clients that don't know the block.
     pub block_type: Byte,
 }
 
 impl S2CPacket for SetBlockPacket {
     fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
+        let block_type = match Block::from_u8(self.block_type) {
+            Some(block) => protocol::block_for_version(block, writer.protocol_version()) as Byte,
+            None => self.block_type,
+        };
+
         writer.write_short(self.x)?;
         writer.write_short(self.y)?;
         writer.write_short(self.z)?;
-        writer.write_byte(self.block_type)
+        writer.write_byte(block_type)
     }
 
     fn id(&self) -> Byte {
@@ -192,7 +238,8 @@ impl S2CPacket for SetBlockPacket {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x07, s2c)]
 pub struct SpawnPlayerPacket {
     pub player_id: SByte,
     pub player_name: PacketString,
@@ -203,23 +250,8 @@ pub struct SpawnPlayerPacket {
     pub pitch: Byte,
 }
 
-impl S2CPacket for SpawnPlayerPacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_sbyte(self.player_id)?;
-        writer.write_packet_string(&self.player_name)?;
-        writer.write_fshort(&self.x)?;
-        writer.write_fshort(&self.y)?;
-        writer.write_fshort(&self.z)?;
-        writer.write_byte(self.yaw)?;
-        writer.write_byte(self.pitch)
-    }
-
-    fn id(&self) -> Byte {
-        0x07
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x08, s2c)]
 pub struct PlayerTeleportPacket {
     pub player_id: SByte,
     pub x: FShort,
@@ -229,22 +261,8 @@ pub struct PlayerTeleportPacket {
     pub pitch: Byte,
 }
 
-impl S2CPacket for PlayerTeleportPacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_sbyte(self.player_id)?;
-        writer.write_fshort(&self.x)?;
-        writer.write_fshort(&self.y)?;
-        writer.write_fshort(&self.z)?;
-        writer.write_byte(self.yaw)?;
-        writer.write_byte(self.pitch)
-    }
-
-    fn id(&self) -> Byte {
-        0x08
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x09, s2c)]
 pub struct PlayerPosOriUpdatePacket {
     pub player_id: SByte,
     pub delta_x: FByte,
@@ -254,22 +272,8 @@ pub struct PlayerPosOriUpdatePacket {
     pub pitch: Byte,
 }
 
-impl S2CPacket for PlayerPosOriUpdatePacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_sbyte(self.player_id)?;
-        writer.write_fbyte(&self.delta_x)?;
-        writer.write_fbyte(&self.delta_y)?;
-        writer.write_fbyte(&self.delta_z)?;
-        writer.write_byte(self.yaw)?;
-        writer.write_byte(self.pitch)
-    }
-
-    fn id(&self) -> Byte {
-        0x09
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x0a, s2c)]
 pub struct PlayerPosUpdatePacket {
     pub player_id: SByte,
     pub delta_x: FByte,
@@ -277,96 +281,159 @@ pub struct PlayerPosUpdatePacket {
     pub delta_z: FByte,
 }
 
-impl S2CPacket for PlayerPosUpdatePacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_sbyte(self.player_id)?;
-        writer.write_fbyte(&self.delta_x)?;
-        writer.write_fbyte(&self.delta_y)?;
-        writer.write_fbyte(&self.delta_z)
-    }
-
-    fn id(&self) -> Byte {
-        0x0a
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x0b, s2c)]
 pub struct PlayerOriUpdatePacket {
     pub player_id: SByte,
     pub yaw: Byte,
     pub pitch: Byte,
 }
 
-impl S2CPacket for PlayerOriUpdatePacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_sbyte(self.player_id)?;
-        writer.write_byte(self.yaw)?;
-        writer.write_byte(self.pitch)
-    }
-
-    fn id(&self) -> Byte {
-        0x0b
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x0c, s2c)]
 pub struct DespawnPlayerPacket {
     pub player_id: SByte,
 }
 
-impl S2CPacket for DespawnPlayerPacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_sbyte(self.player_id)
-    }
-
-    fn id(&self) -> Byte {
-        0x0c
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x0d, s2c)]
 pub struct MessagePacket {
     pub player_id: SByte,
     pub message: PacketString,
 }
 
-impl S2CPacket for MessagePacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_sbyte(self.player_id)?;
-        writer.write_packet_string(&self.message)
-    }
-
-    fn id(&self) -> Byte {
-        0x0d
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x0e, s2c)]
 pub struct DisconnectPlayerPacket {
     pub disconnect_reason: PacketString,
 }
 
-impl S2CPacket for DisconnectPlayerPacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_packet_string(&self.disconnect_reason)
-    }
-
-    fn id(&self) -> Byte {
-        0x0e
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, ClassicPacket)]
+#[packet(id = 0x0f, s2c, min_version = 7)]
 pub struct UpdateUserTypePacket {
     pub user_type: Byte,
 }
 
-impl S2CPacket for UpdateUserTypePacket {
-    fn serialise(&self, writer: &mut PacketWriter) -> Result<()> {
-        writer.write_byte(self.user_type)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::extension::{ExtEntryPacket, ExtInfoPacket};
+
+    fn written(packet: &dyn S2CPacket) -> Vec<u8> {
+        let mut writer = PacketWriter::new_empty();
+        writer.write_packet(packet).unwrap();
+        writer.into_inner()
+    }
+
+    #[test]
+    fn packet_sizes() {
+        let name = || PacketString::truncated("name");
+
+        // Including the packet ID
+        let sizes: [(&dyn S2CPacket, usize); 13] = [
+            (&PingPacket, 1),
+            (&LevelInitPacket, 1),
+            (
+                &LevelFinalisePacket {
+                    x_size: 1,
+                    y_size: 2,
+                    z_size: 3,
+                },
+                7,
+            ),
+            (
+                &SpawnPlayerPacket {
+                    player_id: 0,
+                    player_name: name(),
+                    x: FShort(0),
+                    y: FShort(0),
+                    z: FShort(0),
+                    yaw: 0,
+                    pitch: 0,
+                },
+                74,
+            ),
+            (
+                &PlayerTeleportPacket {
+                    player_id: 0,
+                    x: FShort(0),
+                    y: FShort(0),
+                    z: FShort(0),
+                    yaw: 0,
+                    pitch: 0,
+                },
+                10,
+            ),
+            (
+                &PlayerPosOriUpdatePacket {
+                    player_id: 0,
+                    delta_x: FByte(0),
+                    delta_y: FByte(0),
+                    delta_z: FByte(0),
+                    yaw: 0,
+                    pitch: 0,
+                },
+                7,
+            ),
+            (
+                &PlayerPosUpdatePacket {
+                    player_id: 0,
+                    delta_x: FByte(0),
+                    delta_y: FByte(0),
+                    delta_z: FByte(0),
+                },
+                5,
+            ),
+            (
+                &PlayerOriUpdatePacket {
+                    player_id: 0,
+                    yaw: 0,
+                    pitch: 0,
+                },
+                4,
+            ),
+            (&DespawnPlayerPacket { player_id: 0 }, 2),
+            (
+                &MessagePacket {
+                    player_id: 0,
+                    message: name(),
+                },
+                66,
+            ),
+            (
+                &DisconnectPlayerPacket {
+                    disconnect_reason: name(),
+                },
+                65,
+            ),
+            (
+                &ExtInfoPacket {
+                    app_name: name(),
+                    extension_count: 1,
+                },
+                67,
+            ),
+            (
+                &ExtEntryPacket {
+                    ext_name: name(),
+                    version: 1,
+                },
+                69,
+            ),
+        ];
+
+        for (packet, size) in sizes {
+            assert_eq!(written(packet).len(), size, "{packet:?}");
+        }
     }
 
-    fn id(&self) -> Byte {
-        0x0f
+    #[test]
+    fn writes_fields_in_order() {
+        let data = written(&LevelFinalisePacket {
+            x_size: 256,
+            y_size: 64,
+            z_size: -1,
+        });
+        assert_eq!(data, [0x04, 0x01, 0x00, 0x00, 0x40, 0xff, 0xff]);
     }
 }
@@ -1,9 +1,10 @@
-use anyhow::Ok;
 use anyhow::Result;
 use glam::Vec3;
-use tokio::sync::mpsc;
 
+use crate::networking::protocol;
+use crate::networking::queue::OutboundQueue;
 use crate::networking::util::to_angle_byte;
+use crate::networking::Byte;
 use crate::networking::FByte;
 use crate::networking::FShort;
 use crate::networking::Short;
@@ -14,14 +15,19 @@ use crate::world::Rotation;
 use super::LevelDataChunkPacket;
 use super::LevelFinalisePacket;
 use super::LevelInitPacket;
-use super::S2CPacket;
 
 const CHUNK_SIZE: usize = 1024;
 
-pub fn send_world(world: &BlockWorld, sender: &mpsc::Sender<Box<dyn S2CPacket>>) -> Result<()> {
-    sender.blocking_send(Box::new(LevelInitPacket {}))?;
+/// Blocks the client's protocol version doesn't have are replaced with ones it does.
+pub fn send_world(
+    world: &BlockWorld,
+    protocol_version: Byte,
+    sender: &OutboundQueue,
+) -> Result<()> {
+    sender.send(Box::new(LevelInitPacket {}));
 
-    let serialised = world.serialise()?;
+    let serialised =
+        world.serialise_with(|block| protocol::block_for_version(block, protocol_version))?;
 
     for (i, chunk) in serialised.chunks(CHUNK_SIZE).enumerate() {
         let mut chunk_data = chunk.to_vec();
@@ -29,18 +35,18 @@ pub fn send_world(world: &BlockWorld, sender: &mpsc::Sender<Box<dyn S2CPacket>>)
         let chunk_data = chunk_data.try_into().unwrap();
         let percent_complete = ((i * CHUNK_SIZE * 100) / serialised.len()) as u8;
 
-        sender.blocking_send(Box::new(LevelDataChunkPacket {
+        sender.send(Box::new(LevelDataChunkPacket {
             chunk_length: chunk.len() as Short,
             chunk_data,
             percent_complete,
-        }))?;
+        }));
     }
 
-    sender.blocking_send(Box::new(LevelFinalisePacket {
+    sender.send(Box::new(LevelFinalisePacket {
         x_size: world.dims().x as Short,
         y_size: world.dims().y as Short,
         z_size: world.dims().z as Short,
-    }))?;
+    }));
 
     Ok(())
 }
@@ -51,6 +57,8 @@ pub fn send_world(world: &BlockWorld, sender: &mpsc::Sender<Box<dyn S2CPacket>>)
 /// pos and rot 1 are the original positions and rotations of the player
 ///
 /// pos and rot 2 are the new positions and rotations of the player
+///
+/// Relative updates may be dropped by a lagging client's queue, in which case the next update is sent as a teleport.
 pub fn send_player_move_packet(
     pos1: Vec3,
     pos2: Vec3,
@@ -58,54 +66,61 @@ pub fn send_player_move_packet(
     rot2: Rotation,
     teleport_threshold: f32,
     player_id: PlayerId,
-    sender: &mpsc::Sender<Box<dyn S2CPacket>>,
-) -> Result<()> {
+    sender: &OutboundQueue,
+) {
     let delta_distance = pos1.distance(pos2);
     let rotation_changed = rot1 != rot2;
     let position_changed = pos1 != pos2;
 
-    if delta_distance < teleport_threshold {
+    if delta_distance < teleport_threshold && !sender.take_desync(player_id) {
         let delta_pos = pos2 - pos1;
 
         if position_changed && rotation_changed {
-            return Ok(
-                sender.blocking_send(Box::new(super::PlayerPosOriUpdatePacket {
+            sender.send_movement(
+                player_id,
+                Box::new(super::PlayerPosOriUpdatePacket {
                     player_id,
                     pitch: to_angle_byte(rot2.pitch),
                     yaw: to_angle_byte(rot2.yaw),
                     delta_x: FByte::from(delta_pos.x),
                     delta_y: FByte::from(delta_pos.y),
                     delta_z: FByte::from(delta_pos.z),
-                }))?,
+                }),
             );
-        }
-
-        if rotation_changed {
-            return Ok(sender.blocking_send(Box::new(super::PlayerOriUpdatePacket {
+        } else if rotation_changed {
+            sender.send_movement(
                 player_id,
-                pitch: to_angle_byte(rot2.pitch),
-                yaw: to_angle_byte(rot2.yaw),
-            }))?);
-        }
-
-        if position_changed {
-            return Ok(sender.blocking_send(Box::new(super::PlayerPosUpdatePacket {
+                Box::new(super::PlayerOriUpdatePacket {
+                    player_id,
+                    pitch: to_angle_byte(rot2.pitch),
+                    yaw: to_angle_byte(rot2.yaw),
+                }),
+            );
+        } else if position_changed {
+            sender.send_movement(
                 player_id,
-                delta_x: FByte::from(delta_pos.x),
-                delta_y: FByte::from(delta_pos.y),
-                delta_z: FByte::from(delta_pos.z),
-            }))?);
+                Box::new(super::PlayerPosUpdatePacket {
+                    player_id,
+                    delta_x: FByte::from(delta_pos.x),
+                    delta_y: FByte::from(delta_pos.y),
+                    delta_z: FByte::from(delta_pos.z),
+                }),
+            );
         }
 
-        return Ok(());
+        return;
     }
 
-    Ok(sender.blocking_send(Box::new(super::PlayerTeleportPacket {
+    // Teleports are absolute, so they can't leave the client out of sync if dropped
+    sender.send_movement(
         player_id,
-        pitch: to_angle_byte(rot2.pitch),
-        yaw: to_angle_byte(rot2.yaw),
-        x: FShort::from(pos2.x),
-        y: FShort::from(pos2.y),
-        z: FShort::from(pos2.z),
-    }))?)
+        Box::new(super::PlayerTeleportPacket {
+            player_id,
+            pitch: to_angle_byte(rot2.pitch),
+            yaw: to_angle_byte(rot2.yaw),
+            x: FShort::from(pos2.x),
+            y: FShort::from(pos2.y),
+            z: FShort::from(pos2.z),
+        }),
+    );
 }
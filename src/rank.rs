@@ -0,0 +1,418 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{bail, Context, Result};
+use evenio::component::Component;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::networking::Byte;
+
+/// The user type of players the client treats as operators, letting them break bedrock.
+pub const OPERATOR_USER_TYPE: Byte = 0x64;
+pub const PLAYER_USER_TYPE: Byte = 0x00;
+
+/// A set of permission nodes given to every player with the rank.
+///
+/// A node ending in `.*` grants every node below it, and `*` grants everything.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rank {
+    /// A rank whose permissions this one has as well.
+    pub inherits: Option<String>,
+    pub permissions: Vec<String>,
+    /// Whether clients show players with this rank, or one inheriting it, as operators.
+    pub operator: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RankFile {
+    /// The rank of players that weren't given one.
+    default_rank: String,
+    ranks: BTreeMap<String, Rank>,
+    /// Lowercase player names and their ranks.
+    players: BTreeMap<String, String>,
+}
+
+impl Default for RankFile {
+    fn default() -> Self {
+        let rank = |inherits: Option<&str>, permissions: &[&str], operator| Rank {
+            inherits: inherits.map(Into::into),
+            permissions: permissions.iter().map(|&node| node.into()).collect(),
+            operator,
+        };
+
+        Self {
+            default_rank: "guest".into(),
+            ranks: BTreeMap::from([
+                ("guest".into(), rank(None, &["vintage.build"], false)),
+                (
+                    "builder".into(),
+                    rank(Some("guest"), &["vintage.command.tp"], false),
+                ),
+                (
+                    "op".into(),
+                    rank(
+                        Some("builder"),
+                        &[
+                            "vintage.command.*",
+                            "vintage.build.bypass",
+                            "vintage.join.full",
+                        ],
+                        true,
+                    ),
+                ),
+                ("owner".into(), rank(Some("op"), &["*"], true)),
+            ]),
+            players: BTreeMap::new(),
+        }
+    }
+}
+
+impl RankFile {
+    fn validate(&self) -> Result<()> {
+        if !self.ranks.contains_key(&self.default_rank) {
+            bail!("The default rank {} doesn't exist", self.default_rank);
+        }
+
+        for (name, rank) in &self.ranks {
+            // Any chain longer than the number of ranks has to loop
+            let mut inherits = rank.inherits.as_ref();
+            for _ in 0..=self.ranks.len() {
+                let Some(parent) = inherits else {
+                    break;
+                };
+                let Some(parent) = self.ranks.get(parent) else {
+                    bail!("Rank {name} inherits from {parent}, which doesn't exist");
+                };
+                inherits = parent.inherits.as_ref();
+            }
+            if inherits.is_some() {
+                bail!("The ranks {name} inherits from form a loop");
+            }
+        }
+
+        for (player, rank) in &self.players {
+            if !self.ranks.contains_key(rank) {
+                bail!("{player} has the rank {rank}, which doesn't exist");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rank_of(&self, username: &str) -> &str {
+        self.players
+            .get(&username.to_ascii_lowercase())
+            .unwrap_or(&self.default_rank)
+    }
+
+    /// The rank of `username` followed by the ranks it inherits from, with their names.
+    fn chain(&self, username: &str) -> impl Iterator<Item = (&str, &Rank)> {
+        let mut next = Some(self.rank_of(username));
+
+        std::iter::from_fn(move || {
+            let name = next?;
+            let rank = self.ranks.get(name)?;
+            next = rank.inherits.as_deref();
+            Some((name, rank))
+        })
+    }
+}
+
+/// Whether `granted` grants `node`.
+fn grants(granted: &str, node: &str) -> bool {
+    match granted.strip_suffix('*') {
+        Some(prefix) => (prefix.is_empty() || prefix.ends_with('.')) && node.starts_with(prefix),
+        None => granted == node,
+    }
+}
+
+/// Player ranks and the permissions they grant, saved to a file whenever a player's rank changes.
+///
+/// Clones share the same ranks. Change ranks through [`crate::event::SetRankEvent`] so that online players are updated.
+#[derive(Debug, Clone, Component)]
+pub struct Ranks {
+    path: Option<PathBuf>,
+    file: Arc<RwLock<RankFile>>,
+}
+
+impl Ranks {
+    /// The default ranks, only kept in memory.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            file: Arc::default(),
+        }
+    }
+
+    /// Loads the ranks from `path`, writing the default ranks there if it doesn't exist.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let ranks = Self {
+            path: Some(path.to_owned()),
+            file: Arc::default(),
+        };
+
+        if path.exists() {
+            let data = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read ranks from {}", path.display()))?;
+            let file = toml::from_str::<RankFile>(&data)
+                .with_context(|| format!("Invalid ranks in {}", path.display()))?;
+            file.validate()
+                .with_context(|| format!("Invalid ranks in {}", path.display()))?;
+
+            *ranks.file.write().unwrap() = file;
+        } else {
+            ranks.save(&ranks.file.read().unwrap())?;
+        }
+
+        Ok(ranks)
+    }
+
+    pub fn rank_of(&self, username: &str) -> String {
+        self.file.read().unwrap().rank_of(username).to_string()
+    }
+
+    pub fn get(&self, rank: &str) -> Option<Rank> {
+        self.file.read().unwrap().ranks.get(rank).cloned()
+    }
+
+    /// The names of every rank.
+    pub fn ranks(&self) -> Vec<String> {
+        self.file.read().unwrap().ranks.keys().cloned().collect()
+    }
+
+    /// Whether the rank of `username`, or one it inherits from, grants `node`.
+    pub fn has_permission(&self, username: &str, node: &str) -> bool {
+        self.file
+            .read()
+            .unwrap()
+            .chain(username)
+            .flat_map(|(_, rank)| &rank.permissions)
+            .any(|granted| grants(granted, node))
+    }
+
+    /// Whether `rank` is the rank of `username` or one it inherits from, so that they may hand it out.
+    pub fn covers(&self, username: &str, rank: &str) -> bool {
+        self.file
+            .read()
+            .unwrap()
+            .chain(username)
+            .any(|(name, _)| name == rank)
+    }
+
+    /// The user type to send to the client of `username`.
+    pub fn user_type(&self, username: &str) -> Byte {
+        if self
+            .file
+            .read()
+            .unwrap()
+            .chain(username)
+            .any(|(_, rank)| rank.operator)
+        {
+            OPERATOR_USER_TYPE
+        } else {
+            PLAYER_USER_TYPE
+        }
+    }
+
+    /// Doesn't update the player if they are online, see [`crate::event::SetRankEvent`] for that.
+    pub fn set_rank(&self, username: &str, rank: &str) -> Result<()> {
+        self.change_rank(username, rank)?.write()
+    }
+
+    /// Like [`Self::set_rank`], but leaves writing the rank file to the caller, so that it can be done on another thread.
+    pub fn change_rank(&self, username: &str, rank: &str) -> Result<RankSave> {
+        let mut file = self.file.write().unwrap();
+
+        if !file.ranks.contains_key(rank) {
+            bail!("There is no rank called {rank}");
+        }
+
+        file.players
+            .insert(username.to_ascii_lowercase(), rank.to_string());
+
+        info!("{username} now has the rank {rank}");
+        self.snapshot(&file)
+    }
+
+    fn save(&self, file: &RankFile) -> Result<()> {
+        self.snapshot(file)?.write()
+    }
+
+    fn snapshot(&self, file: &RankFile) -> Result<RankSave> {
+        Ok(RankSave {
+            path: self.path.clone(),
+            data: toml::to_string_pretty(file)?,
+        })
+    }
+}
+
+/// The ranks as they were after a change, waiting to be written to the rank file.
+#[must_use]
+#[derive(Debug)]
+pub struct RankSave {
+    path: Option<PathBuf>,
+    data: String,
+}
+
+impl RankSave {
+    pub fn write(self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        fs::write(path, self.data)
+            .with_context(|| format!("Failed to save ranks to {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(toml: &str) -> Result<RankFile> {
+        let file = toml::from_str::<RankFile>(toml)?;
+        file.validate()?;
+        Ok(file)
+    }
+
+    fn ranks(toml: &str) -> Ranks {
+        Ranks {
+            path: None,
+            file: Arc::new(RwLock::new(file(toml).unwrap())),
+        }
+    }
+
+    const CHAIN: &str = r#"
+        default_rank = "guest"
+
+        [ranks.guest]
+        permissions = ["vintage.build"]
+
+        [ranks.mod]
+        inherits = "guest"
+        permissions = ["vintage.command.*"]
+
+        [ranks.admin]
+        inherits = "mod"
+        permissions = ["*"]
+        operator = true
+
+        [ranks.other]
+        permissions = ["vintage.command.tp"]
+
+        [players]
+        modder = "mod"
+        boss = "admin"
+        stranger = "other"
+    "#;
+
+    #[test]
+    fn default_ranks_are_valid() {
+        RankFile::default().validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_inheritance_loops() {
+        let looped = file(
+            r#"
+            default_rank = "a"
+            ranks.a = { inherits = "b" }
+            ranks.b = { inherits = "c" }
+            ranks.c = { inherits = "a" }
+            "#,
+        );
+        assert!(looped.unwrap_err().to_string().contains("form a loop"));
+
+        let own_parent = file(
+            r#"
+            default_rank = "a"
+            ranks.a = { inherits = "a" }
+            "#,
+        );
+        assert!(own_parent.is_err());
+    }
+
+    #[test]
+    fn rejects_missing_ranks() {
+        let missing_parent = file(
+            r#"
+            default_rank = "a"
+            ranks.a = { inherits = "b" }
+            "#,
+        );
+        assert_eq!(
+            missing_parent.unwrap_err().to_string(),
+            "Rank a inherits from b, which doesn't exist"
+        );
+
+        assert!(file(r#"default_rank = "nobody""#).is_err());
+
+        let missing_player_rank = file(
+            r#"
+            default_rank = "a"
+            ranks.a = {}
+            players.alice = "b"
+            "#,
+        );
+        assert!(missing_player_rank.is_err());
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(grants("vintage.build", "vintage.build"));
+        assert!(!grants("vintage.build", "vintage.build.bypass"));
+        assert!(grants("vintage.command.*", "vintage.command.tp"));
+        assert!(grants("vintage.command.*", "vintage.command.rank.owner"));
+        assert!(!grants("vintage.command.*", "vintage.commands"));
+        assert!(!grants("vintage.command.*", "vintage.build"));
+        assert!(grants("*", "anything.at.all"));
+        assert!(!grants("vintage*", "vintage.build"));
+    }
+
+    #[test]
+    fn permissions_are_inherited() {
+        let ranks = ranks(CHAIN);
+
+        assert!(ranks.has_permission("nobody", "vintage.build"));
+        assert!(!ranks.has_permission("nobody", "vintage.command.tp"));
+        assert!(ranks.has_permission("Modder", "vintage.build"));
+        assert!(ranks.has_permission("modder", "vintage.command.kick"));
+        assert!(!ranks.has_permission("modder", "vintage.join.full"));
+        assert!(ranks.has_permission("boss", "vintage.join.full"));
+        assert!(!ranks.has_permission("stranger", "vintage.build"));
+
+        assert_eq!(ranks.user_type("modder"), PLAYER_USER_TYPE);
+        assert_eq!(ranks.user_type("boss"), OPERATOR_USER_TYPE);
+    }
+
+    #[test]
+    fn covers_the_inheritance_chain() {
+        let ranks = ranks(CHAIN);
+
+        assert!(ranks.covers("boss", "admin"));
+        assert!(ranks.covers("boss", "mod"));
+        assert!(ranks.covers("boss", "guest"));
+        assert!(!ranks.covers("boss", "other"));
+        assert!(ranks.covers("modder", "guest"));
+        assert!(!ranks.covers("modder", "admin"));
+        assert!(!ranks.covers("stranger", "guest"));
+    }
+
+    #[test]
+    fn set_rank() {
+        let ranks = ranks(CHAIN);
+
+        ranks.set_rank("Newbie", "mod").unwrap();
+        assert_eq!(ranks.rank_of("newbie"), "mod");
+        assert!(ranks.set_rank("newbie", "king").is_err());
+        assert_eq!(ranks.rank_of("newbie"), "mod");
+    }
+}
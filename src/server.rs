@@ -0,0 +1,477 @@
+use std::{future::Future, pin::Pin, sync::Arc, thread, time::Duration};
+
+use anyhow::{Context, Result};
+use evenio::prelude::*;
+use glam::UVec3;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, warn};
+
+use crate::{
+    command::{self, Command, CommandRegistry},
+    config::ServerConfig,
+    default,
+    event::{PlayerDisconnectEvent, SaveWorldEvent, ServerStartEvent, ServerStopEvent},
+    extension::{self, ExtensionRegistry},
+    heartbeat,
+    networking::{
+        access::BanList,
+        c2s::C2SPacketEntry,
+        intercept::{Interceptor, InterceptorChain},
+        listener::{self, ClientMessage, Connections, SessionId},
+        s2c::S2CPacket,
+        ClientPacketRegistry,
+    },
+    rank::Ranks,
+    util::add_periodic_saver,
+    world::{flat_generator, BlockWorld, TickEvent},
+};
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// How many broadcast packets a client may fall behind by before it is kicked.
+const BROADCAST_CAPACITY: usize = 1024;
+const WORLD_STOPPED: &str = "World thread stopped unexpectedly";
+/// How long clients are given to receive their remaining packets on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type Broadcaster = Arc<broadcast::Sender<Arc<Box<dyn S2CPacket>>>>;
+
+pub type LevelGenerator = Box<dyn FnOnce(UVec3, &mut BlockWorld)>;
+type HeartbeatTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Where the server gets its [`BlockWorld`] from.
+pub enum LevelSource {
+    /// Loads the level from the configured path, generating a flat one if it doesn't exist.
+    Config,
+    /// Loads the level from the configured path, using the given generator if it doesn't exist.
+    Generator(LevelGenerator),
+    World(BlockWorld),
+}
+
+/// Everything a plugin may need while it is being installed.
+pub struct PluginContext<'a> {
+    pub world: &'a mut World,
+    pub packets: &'a mut ClientPacketRegistry,
+    pub interceptors: &'a mut InterceptorChain,
+    pub commands: &'a mut CommandRegistry,
+    pub extensions: &'a mut ExtensionRegistry,
+    pub config: &'a ServerConfig,
+    pub broadcaster: &'a Broadcaster,
+}
+
+pub trait Plugin {
+    fn install(self: Box<Self>, ctx: &mut PluginContext);
+}
+
+impl<F: FnOnce(&mut PluginContext)> Plugin for F {
+    fn install(self: Box<Self>, ctx: &mut PluginContext) {
+        self(ctx)
+    }
+}
+
+/// Messages that can be sent to a running [`Server`] through a [`ServerHandle`].
+pub enum ServerCommand {
+    /// Runs the closure on the world thread.
+    Exec(Box<dyn FnOnce(&mut World) + Send>),
+    /// Stops the server, disconnecting players with the given reason or the configured shutdown message.
+    Shutdown(Option<String>),
+}
+
+/// A cloneable handle used to control a [`Server`] from other tasks.
+#[derive(Clone)]
+pub struct ServerHandle {
+    commands: mpsc::Sender<ServerCommand>,
+    bans: BanList,
+    ranks: Ranks,
+    connections: Arc<Connections>,
+}
+
+impl ServerHandle {
+    pub async fn exec<F: FnOnce(&mut World) + Send + 'static>(&self, f: F) -> Result<()> {
+        self.send(ServerCommand::Exec(Box::new(f))).await
+    }
+
+    pub async fn shutdown(&self) -> Result<()> {
+        self.send(ServerCommand::Shutdown(None)).await
+    }
+
+    pub async fn shutdown_with_reason(&self, reason: impl Into<String>) -> Result<()> {
+        self.send(ServerCommand::Shutdown(Some(reason.into())))
+            .await
+    }
+
+    pub async fn send(&self, command: ServerCommand) -> Result<()> {
+        self.commands
+            .send(command)
+            .await
+            .ok()
+            .context("Server is no longer running")
+    }
+
+    pub fn commands(&self) -> mpsc::Sender<ServerCommand> {
+        self.commands.clone()
+    }
+
+    /// The IP ban list. Changes apply to new connections straight away.
+    pub fn bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    /// Player ranks and permissions. Changing a rank through [`crate::event::SetRankEvent`] also updates the player if they are online.
+    pub fn ranks(&self) -> &Ranks {
+        &self.ranks
+    }
+
+    /// Every open connection, including those that haven't logged in yet.
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+
+    /// Kicks the client of `session` without going through the world. Returns whether it was still connected.
+    pub fn kick(&self, session: SessionId, reason: &str) -> bool {
+        self.connections.kick(session, reason)
+    }
+}
+
+pub struct ServerBuilder {
+    config: ServerConfig,
+    level: LevelSource,
+    packets: ClientPacketRegistry,
+    interceptors: InterceptorChain,
+    commands: CommandRegistry,
+    extensions: ExtensionRegistry,
+    plugins: Vec<Box<dyn Plugin>>,
+}
+
+impl ServerBuilder {
+    /// Starts off with the vanilla packets registered and the level loaded as described by `config`.
+    pub fn new(config: ServerConfig) -> Self {
+        let mut packets = ClientPacketRegistry::default();
+        default::add_default_packets(&mut packets);
+        let mut commands = CommandRegistry::default();
+        default::add_default_commands(&mut commands);
+
+        Self {
+            config,
+            level: LevelSource::Config,
+            packets,
+            interceptors: InterceptorChain::default(),
+            commands,
+            extensions: ExtensionRegistry::default(),
+            plugins: Vec::new(),
+        }
+    }
+
+    pub fn level(mut self, level: LevelSource) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Replaces the packet registry, including the vanilla packets.
+    pub fn packet_registry(mut self, packets: ClientPacketRegistry) -> Self {
+        self.packets = packets;
+        self
+    }
+
+    pub fn register_packet<P: C2SPacketEntry>(mut self) -> Self {
+        self.packets.register::<P>();
+        self
+    }
+
+    /// Interceptors run in the order they were added, before those added by plugins.
+    pub fn interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.add(interceptor);
+        self
+    }
+
+    /// Replaces any command with the same name, including the default ones.
+    pub fn command(mut self, command: Command) -> Self {
+        self.commands.register(command);
+        self
+    }
+
+    pub fn extension_registry(mut self, extensions: ExtensionRegistry) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Plugins are installed in the order they were added, after the default handlers.
+    pub fn plugin<P: Plugin + 'static>(mut self, plugin: P) -> Self {
+        self.plugins.push(Box::new(plugin));
+        self
+    }
+
+    pub fn build(self) -> Result<Server> {
+        let Self {
+            config,
+            level,
+            mut packets,
+            mut interceptors,
+            mut commands,
+            mut extensions,
+            plugins,
+        } = self;
+
+        config.validate()?;
+
+        let mut world = World::new();
+
+        let level_path = config.level.path.as_str();
+        let block_world = match level {
+            LevelSource::Config => BlockWorld::new_or_load_from_file(
+                level_path,
+                config.level.dimensions,
+                flat_generator(config.level.ground_height),
+            ),
+            LevelSource::Generator(generator) => {
+                BlockWorld::new_or_load_from_file(level_path, config.level.dimensions, generator)
+            },
+            LevelSource::World(block_world) => block_world,
+        };
+        let block_world_entity = world.spawn();
+        world.insert(block_world_entity, block_world);
+
+        let bans = BanList::load(&config.network.ban_file)?;
+        let bans_entity = world.spawn();
+        world.insert(bans_entity, bans.clone());
+
+        let (broadcaster, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let broadcaster = Arc::new(broadcaster);
+
+        let ranks = Ranks::load(&config.server.rank_file)?;
+        default::add_default_handlers(&mut world, broadcaster.clone(), &config, ranks.clone());
+        add_periodic_saver(&mut world, config.level.save_interval(), level_path);
+
+        let salt = heartbeat::generate_salt();
+        if config.heartbeat.verify_names {
+            if !config.heartbeat.enabled {
+                warn!("Names are verified but the heartbeat is disabled, so only LAN players can join if allowed");
+            }
+            heartbeat::add_name_verifier(&mut world, &config.heartbeat, salt.clone());
+        }
+
+        let heartbeat: Option<HeartbeatTask> = if config.heartbeat.enabled {
+            Some(Box::pin(heartbeat::add_heartbeat(
+                &mut world, &config, salt,
+            )))
+        } else {
+            None
+        };
+
+        for plugin in plugins {
+            plugin.install(&mut PluginContext {
+                world: &mut world,
+                packets: &mut packets,
+                interceptors: &mut interceptors,
+                commands: &mut commands,
+                extensions: &mut extensions,
+                config: &config,
+                broadcaster: &broadcaster,
+            });
+        }
+
+        command::add_command_handlers(&mut world, commands);
+        extension::add_cpe_packets(&mut packets, &extensions);
+        extension::add_cpe_handlers(&mut world, extensions);
+
+        let (commands_tx, commands_rx) = mpsc::channel(32);
+
+        Ok(Server {
+            config,
+            world,
+            packets,
+            interceptors,
+            broadcaster,
+            heartbeat,
+            handle: ServerHandle {
+                commands: commands_tx,
+                bans,
+                ranks,
+                connections: Arc::default(),
+            },
+            commands: commands_rx,
+        })
+    }
+}
+
+enum WorldEvent {
+    Tick,
+    ClientMessage(ClientMessage),
+    Exec(Box<dyn FnOnce(&mut World) + Send>),
+    Stop(String),
+}
+
+/// A fully set up server, ready to be [run](Server::run).
+pub struct Server {
+    config: ServerConfig,
+    world: World,
+    packets: ClientPacketRegistry,
+    interceptors: InterceptorChain,
+    broadcaster: Broadcaster,
+    heartbeat: Option<HeartbeatTask>,
+    handle: ServerHandle,
+    commands: mpsc::Receiver<ServerCommand>,
+}
+
+impl Server {
+    pub fn builder(config: ServerConfig) -> ServerBuilder {
+        ServerBuilder::new(config)
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    /// Requests a shutdown of the server. Has no effect if it is not running.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.handle.shutdown().await
+    }
+
+    /// Starts accepting connections and runs the world until a [`ServerCommand::Shutdown`] is received or the process is asked to terminate.
+    ///
+    /// On shutdown, every client is disconnected, the level is saved and a [`ServerStopEvent`] is sent before this returns.
+    pub async fn run(self) -> Result<()> {
+        let Self {
+            config,
+            mut world,
+            packets,
+            interceptors,
+            broadcaster,
+            heartbeat,
+            // Also keeps the command channel open for as long as the server runs
+            handle,
+            mut commands,
+        } = self;
+
+        info!("Starting");
+
+        let heartbeat = heartbeat.map(tokio::spawn);
+
+        let connections = handle.connections.clone();
+        let (tx, mut rx) = mpsc::channel(32);
+        let listener = tokio::spawn(listener::listen(
+            config.network.clone(),
+            tx,
+            broadcaster,
+            packets,
+            interceptors,
+            connections.clone(),
+            handle.bans.clone(),
+        ));
+
+        let (world_tx, mut world_rx) = mpsc::channel::<WorldEvent>(32);
+
+        let world_thread = thread::Builder::new().name("world".into()).spawn(move || {
+            world.send(ServerStartEvent);
+
+            while let Some(event) = world_rx.blocking_recv() {
+                match event {
+                    WorldEvent::Tick => {
+                        world.send(TickEvent {});
+                    },
+                    WorldEvent::ClientMessage(message) => match message {
+                        ClientMessage::Packet(packet) => {
+                            if let Err(e) = packet.exec(&mut world) {
+                                error!("failed to execute packet handler: {e}")
+                            }
+                        },
+                        ClientMessage::Disconnect(session) => {
+                            world.send(PlayerDisconnectEvent(session))
+                        },
+                        ClientMessage::Flood(event) => world.send(event),
+                    },
+                    WorldEvent::Exec(f) => f(&mut world),
+                    WorldEvent::Stop(reason) => {
+                        world.send(SaveWorldEvent);
+                        world.send(ServerStopEvent { reason });
+                        break;
+                    },
+                }
+            }
+        })?;
+
+        let mut interval = tokio::time::interval(TICK_INTERVAL);
+        let terminated = terminate_signal();
+        tokio::pin!(terminated);
+
+        let reason = loop {
+            tokio::select! {
+                Some(packet) = rx.recv() => {
+                    world_tx.send(WorldEvent::ClientMessage(packet)).await.ok().context(WORLD_STOPPED)?;
+                }
+                _ = interval.tick() => {
+                    world_tx.send(WorldEvent::Tick).await.ok().context(WORLD_STOPPED)?;
+                }
+                Some(command) = commands.recv() => match command {
+                    ServerCommand::Exec(f) => world_tx.send(WorldEvent::Exec(f)).await.ok().context(WORLD_STOPPED)?,
+                    ServerCommand::Shutdown(reason) => break reason,
+                },
+                _ = &mut terminated => break None,
+            }
+        };
+
+        info!("Stopping");
+
+        listener.abort();
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.abort();
+        }
+
+        let reason = reason.unwrap_or_else(|| config.server.shutdown_message.clone());
+        connections.disconnect_all(&reason);
+
+        world_tx
+            .send(WorldEvent::Stop(reason))
+            .await
+            .ok()
+            .context(WORLD_STOPPED)?;
+        drop(world_tx);
+        tokio::task::spawn_blocking(move || world_thread.join())
+            .await?
+            .ok()
+            .context("World thread panicked")?;
+
+        // Nothing is left to handle client messages, but dropping them lets the client tasks finish
+        drop(rx);
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, connections.wait_closed())
+            .await
+            .is_err()
+        {
+            warn!(
+                remaining = connections.len(),
+                "Timed out waiting for clients to disconnect"
+            );
+        }
+
+        info!("Stopped");
+
+        Ok(())
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn terminate_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = sigterm.recv() => {},
+                }
+                return;
+            },
+            Err(e) => warn!("Failed to listen for SIGTERM: {e}"),
+        }
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Failed to listen for Ctrl-C: {e}");
+        std::future::pending::<()>().await;
+    }
+}
@@ -1,9 +1,17 @@
 use std::time::{Duration, Instant};
 
-use evenio::{component::Component, event::Receiver, fetch::Single, world::World};
-use tracing::info;
+use evenio::{
+    component::Component,
+    event::{Receiver, Sender},
+    fetch::Single,
+    world::World,
+};
+use tracing::{error, info};
 
-use crate::world::{BlockWorld, TickEvent};
+use crate::{
+    event::{SaveWorldEvent, WorldSavedEvent},
+    world::{BlockWorld, TickEvent},
+};
 
 #[derive(Component)]
 struct WorldSaver {
@@ -24,19 +32,37 @@ pub fn add_periodic_saver(world: &mut World, interval: Duration, save_path: &str
     );
 
     world.add_handler(tick_handler);
+    world.add_handler(save_handler);
 }
 
 #[allow(private_interfaces)]
 pub fn tick_handler(
     _: Receiver<TickEvent>,
+    Single(saver): Single<&WorldSaver>,
+    mut sender: Sender<SaveWorldEvent>,
+) {
+    if saver.last_save.elapsed() >= saver.interval {
+        sender.send(SaveWorldEvent);
+    }
+}
+
+#[allow(private_interfaces)]
+pub fn save_handler(
+    _: Receiver<SaveWorldEvent>,
     Single(saver): Single<&mut WorldSaver>,
     Single(world): Single<&BlockWorld>,
+    mut sender: Sender<WorldSavedEvent>,
 ) {
-    if saver.last_save.elapsed() >= saver.interval {
-        saver.last_save = Instant::now();
-        // TODO: This might be good if it was on another thread
-        world.save_to_file(saver.save_path.as_str()).unwrap();
+    saver.last_save = Instant::now();
 
-        info!("Saved world")
+    // TODO: This might be good if it was on another thread
+    match world.save_to_file(saver.save_path.as_str()) {
+        Ok(()) => {
+            info!("Saved world");
+            sender.send(WorldSavedEvent {
+                path: saver.save_path.clone(),
+            });
+        },
+        Err(e) => error!("Failed to save world to {}: {e}", saver.save_path),
     }
 }
@@ -1,8 +1,10 @@
 use std::{
+    collections::HashMap,
     fs::{self, File},
     io::{Cursor, Read, Write},
     net::SocketAddr,
     ops::Sub,
+    sync::Arc,
 };
 
 use anyhow::Result;
@@ -10,14 +12,19 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use enum_primitive::FromPrimitive;
 use evenio::{component::Component, entity::EntityId, event::Event};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
-use glam::{UVec3, Vec3};
-use tokio::sync::mpsc;
+use glam::{uvec3, IVec3, UVec3, Vec3};
+use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-use crate::networking::s2c::S2CPacket;
+use crate::networking::{
+    listener::{Activity, SessionId},
+    queue::OutboundQueue,
+    Byte,
+};
 
 enum_from_primitive! {
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Block {
     Air,
     Stone,
@@ -36,6 +43,7 @@ pub enum Block {
     GoldOre,
     IronOre,
     CoalOre,
+    #[serde(rename = "wood")]
     Woord,
     Leaves,
     Sponge,
@@ -65,8 +73,10 @@ pub enum Block {
     DoubleSlab,
     Slab,
     Bricks,
+    #[serde(rename = "tnt")]
     TNT,
     Bookshelf,
+    #[serde(rename = "mossy_cobblestone")]
     MossyCobbleStone,
     Obsidian,
 }
@@ -82,8 +92,38 @@ pub struct Player {
 
 #[derive(Component, Debug)]
 pub struct ClientConnection {
-    pub sender: mpsc::Sender<Box<dyn S2CPacket>>,
+    pub session: SessionId,
+    pub sender: Arc<OutboundQueue>,
     pub addr: SocketAddr,
+    pub activity: Arc<Activity>,
+    /// Decides which packets and blocks the client understands.
+    pub protocol_version: Byte,
+}
+
+/// The entity of every connection that has sent its PlayerIdentPacket, kept up to date as [`ClientConnection`]s are inserted and despawned.
+#[derive(Component, Debug, Default)]
+pub struct Sessions(HashMap<SessionId, EntityId>);
+
+impl Sessions {
+    pub fn get(&self, session: SessionId) -> Option<EntityId> {
+        self.0.get(&session).copied()
+    }
+
+    pub fn insert(&mut self, session: SessionId, entity_id: EntityId) {
+        self.0.insert(session, entity_id);
+    }
+
+    pub fn remove(&mut self, session: SessionId) -> Option<EntityId> {
+        self.0.remove(&session)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 #[derive(Component)]
@@ -133,21 +173,34 @@ impl BlockWorld {
         world
     }
 
+    /// Whether `pos` is inside the world.
+    pub fn contains(&self, pos: IVec3) -> bool {
+        pos.cmpge(IVec3::ZERO).all() && pos.as_uvec3().cmplt(self.dimensions).all()
+    }
+
+    /// Panics if `pos` is outside the world.
     pub fn get_block(&self, pos: UVec3) -> Block {
         self.blocks[self.pos_to_index(pos)]
     }
 
+    /// Panics if `pos` is outside the world.
     pub fn set_block(&mut self, pos: UVec3, block: Block) {
         debug!("Setting block at: {pos:?}");
         let index = self.pos_to_index(pos);
         self.blocks[index] = block;
     }
 
+    /// Blocks are stored in the order clients expect level data in, x first, then z, then y. Level files use the same order.
     fn pos_to_index(&self, pos: UVec3) -> usize {
-        (pos.x + pos.z * self.dims().z + pos.y * self.dims().x * self.dims().z) as usize
+        (pos.x + pos.z * self.dims().x + pos.y * self.dims().x * self.dims().z) as usize
     }
 
     pub fn serialise(&self) -> Result<Vec<u8>> {
+        self.serialise_with(|block| block)
+    }
+
+    /// Serialises the world with every block passed through `convert`, such as to replace blocks a client doesn't know.
+    pub fn serialise_with(&self, convert: impl Fn(Block) -> Block) -> Result<Vec<u8>> {
         let mut data = GzEncoder::new(
             Vec::with_capacity(
                 (self.dimensions.x * self.dimensions.y * self.dimensions.z) as usize,
@@ -160,7 +213,7 @@ impl BlockWorld {
         data.write_all(
             self.blocks
                 .iter()
-                .map(|&block| block as u8)
+                .map(|&block| convert(block) as u8)
                 .collect::<Vec<_>>()
                 .as_slice(),
         )
@@ -232,6 +285,21 @@ impl BlockWorld {
     }
 }
 
+/// Fills everything below `ground_height` with dirt and covers it with a layer of grass.
+pub fn flat_generator(ground_height: u32) -> impl FnOnce(UVec3, &mut BlockWorld) {
+    move |dims, world| {
+        for x in 0..dims.x {
+            for z in 0..dims.z {
+                for y in 0..ground_height {
+                    world.set_block(uvec3(x, y, z), Block::Dirt);
+                }
+
+                world.set_block(uvec3(x, ground_height, z), Block::GrassBlock);
+            }
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct PlayerIdAllocator {
     occupation: Vec<Option<EntityId>>,
@@ -244,15 +312,16 @@ impl PlayerIdAllocator {
         }
     }
 
-    pub fn alloc(&mut self, entity_id: EntityId) -> PlayerId {
+    /// Returns [`None`] if all ids are taken.
+    pub fn alloc(&mut self, entity_id: EntityId) -> Option<PlayerId> {
         for (id, occupied) in self.occupation.iter_mut().enumerate() {
             if occupied.is_none() {
                 *occupied = Some(entity_id);
-                return id as PlayerId;
+                return Some(id as PlayerId);
             }
         }
 
-        panic!("No more player ids available");
+        None
     }
 
     pub fn free(&mut self, id: PlayerId) {
@@ -275,3 +344,62 @@ impl PlayerIdAllocator {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_order(world: &BlockWorld) -> Vec<u8> {
+        let data = world.serialise().unwrap();
+        let mut data = GzDecoder::new(data.as_slice());
+        let mut buffer = Vec::new();
+        data.read_to_end(&mut buffer).unwrap();
+        buffer.split_off(4)
+    }
+
+    #[test]
+    fn blocks_are_stored_in_client_order() {
+        for dims in [uvec3(4, 3, 2), uvec3(2, 3, 4), uvec3(3, 3, 3)] {
+            let mut world = BlockWorld::new(dims, |_, _| {});
+            let corner = dims - UVec3::ONE;
+            world.set_block(uvec3(1, 0, 0), Block::Stone);
+            world.set_block(uvec3(0, 0, 1), Block::Dirt);
+            world.set_block(uvec3(0, 1, 0), Block::GrassBlock);
+            world.set_block(corner, Block::Obsidian);
+
+            let blocks = client_order(&world);
+            let index = |pos: UVec3| (pos.x + pos.z * dims.x + pos.y * dims.x * dims.z) as usize;
+            assert_eq!(blocks[index(uvec3(1, 0, 0))], Block::Stone as u8, "{dims}");
+            assert_eq!(blocks[index(uvec3(0, 0, 1))], Block::Dirt as u8, "{dims}");
+            assert_eq!(
+                blocks[index(uvec3(0, 1, 0))],
+                Block::GrassBlock as u8,
+                "{dims}"
+            );
+            assert_eq!(
+                blocks.last().copied(),
+                Some(Block::Obsidian as u8),
+                "{dims}"
+            );
+            assert_eq!(
+                blocks.iter().filter(|&&block| block != 0).count(),
+                4,
+                "{dims}"
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dims = uvec3(5, 3, 2);
+        let mut world = BlockWorld::new(dims, flat_generator(1));
+        world.set_block(uvec3(4, 2, 1), Block::Glass);
+
+        let loaded = BlockWorld::deserialise(&world.serialise().unwrap(), dims).unwrap();
+        assert_eq!(loaded.get_block(uvec3(4, 2, 1)), Block::Glass);
+        assert_eq!(loaded.get_block(uvec3(4, 1, 1)), Block::GrassBlock);
+        assert_eq!(loaded.get_block(uvec3(0, 0, 1)), Block::Dirt);
+        assert_eq!(loaded.get_block(uvec3(3, 2, 1)), Block::Air);
+        assert!(BlockWorld::deserialise(&world.serialise().unwrap(), uvec3(5, 4, 2)).is_err());
+    }
+}
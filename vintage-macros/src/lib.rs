@@ -0,0 +1,181 @@
+//! Derive macros for the `vintage` crate.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Index, LitInt, Member, Result};
+
+/// Implements `C2SPacketEntry` and/or `S2CPacket` for a struct whose fields are all `PacketField`s, read and written in declaration order.
+///
+/// ```
+/// use vintage::networking::{c2s::C2SPacketEntry, Byte, ClassicPacket, Short};
+/// # use vintage::networking::{c2s::C2SPacket, listener::ClientInfo};
+///
+/// #[derive(Debug, ClassicPacket)]
+/// #[packet(id = 0x05, c2s)]
+/// pub struct SetBlockPacket {
+///     x: Short,
+///     y: Short,
+///     z: Short,
+///     mode: Byte,
+///     block_type: Byte,
+/// }
+/// # impl C2SPacket for SetBlockPacket {
+/// #     fn exec(&self, _: &mut evenio::world::World, _: &ClientInfo) -> anyhow::Result<()> {
+/// #         Ok(())
+/// #     }
+/// # }
+///
+/// assert_eq!(SetBlockPacket::SIZE, 8);
+/// ```
+///
+/// `c2s` generates `C2SPacketEntry`, which needs a handwritten `C2SPacket` impl alongside it. `s2c` generates `S2CPacket`, and `min_version = 7` keeps the packet from being sent to clients of older protocol versions. The packet size is the sum of the `PacketField::SIZE` of every field.
+#[proc_macro_derive(ClassicPacket, attributes(packet))]
+pub fn derive_classic_packet(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+struct PacketAttr {
+    id: LitInt,
+    c2s: bool,
+    s2c: bool,
+    min_version: Option<LitInt>,
+}
+
+fn parse_packet_attr(input: &DeriveInput) -> Result<PacketAttr> {
+    let mut id = None;
+    let mut c2s = false;
+    let mut s2c = false;
+    let mut min_version = None;
+
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                let lit: LitInt = meta.value()?.parse()?;
+                lit.base10_parse::<u8>()?;
+                id = Some(lit);
+            } else if meta.path.is_ident("c2s") {
+                c2s = true;
+            } else if meta.path.is_ident("s2c") {
+                s2c = true;
+            } else if meta.path.is_ident("min_version") {
+                let lit: LitInt = meta.value()?.parse()?;
+                lit.base10_parse::<u8>()?;
+                min_version = Some(lit);
+            } else {
+                return Err(meta.error("expected `id = ...`, `min_version = ...`, `c2s` or `s2c`"));
+            }
+
+            Ok(())
+        })?;
+    }
+
+    let id = id.ok_or_else(|| {
+        Error::new_spanned(&input.ident, "missing packet ID, add #[packet(id = 0x..)]")
+    })?;
+
+    if !c2s && !s2c {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "packet needs a direction, add `c2s`, `s2c` or both to #[packet(..)]",
+        ));
+    }
+
+    Ok(PacketAttr {
+        id,
+        c2s,
+        s2c,
+        min_version,
+    })
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    let attr = parse_packet_attr(&input)?;
+
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "ClassicPacket can only be derived for structs",
+        ));
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let id = &attr.id;
+
+    let networking = quote!(::vintage::networking);
+    let field = quote!(#networking::PacketField);
+
+    let types = data.fields.iter().map(|f| &f.ty).collect::<Vec<_>>();
+    let members = data
+        .fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| match &f.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        })
+        .collect::<Vec<_>>();
+
+    // Struct expressions accept `0: value` for tuple structs too
+    let construct = match &data.fields {
+        Fields::Unit => quote!({
+            let _ = reader;
+            Self
+        }),
+        _ => quote!(Self { #(#members: #field::read(reader)?,)* }),
+    };
+    let write = if members.is_empty() {
+        quote!(let _ = writer;)
+    } else {
+        quote!(#(#field::write(&self.#members, writer)?;)*)
+    };
+
+    let min_version = attr.min_version.as_ref().map(|version| {
+        quote! {
+            fn min_protocol_version(&self) -> #networking::Byte {
+                #version
+            }
+        }
+    });
+
+    let mut output = TokenStream2::new();
+
+    if attr.c2s {
+        output.extend(quote! {
+            impl #impl_generics #networking::c2s::C2SPacketEntry for #name #ty_generics #where_clause {
+                const ID: #networking::Byte = #id;
+                const SIZE: usize = 0 #(+ <#types as #field>::SIZE)*;
+
+                fn deserialise(
+                    reader: &mut #networking::c2s::PacketReader,
+                ) -> ::anyhow::Result<::std::boxed::Box<dyn #networking::c2s::C2SPacket>> {
+                    ::std::result::Result::Ok(::std::boxed::Box::new(#construct))
+                }
+            }
+        });
+    }
+
+    if attr.s2c {
+        output.extend(quote! {
+            impl #impl_generics #networking::s2c::S2CPacket for #name #ty_generics #where_clause {
+                fn serialise(&self, writer: &mut #networking::s2c::PacketWriter) -> ::anyhow::Result<()> {
+                    #write
+                    ::std::result::Result::Ok(())
+                }
+
+                fn id(&self) -> #networking::Byte {
+                    #id
+                }
+
+                #min_version
+            }
+        });
+    }
+
+    Ok(output)
+}